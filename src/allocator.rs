@@ -0,0 +1,339 @@
+use crate::bins::Bins;
+use crate::bump::Bump;
+use crate::config::Config;
+use crate::heap::{Block, BlockId, Cache, Fit, Heap, HeapError};
+use crate::pages::{PageHeap, PageKind};
+
+// What the renderer needs to know about a block. Offsets are in bytes from
+// the start of the heap, and the pointer handed out for an allocated block
+// is `header` bytes into it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlockState {
+    Free,
+    // A free block that new blocks are carved from, like ptmalloc's top chunk.
+    Top,
+    Allocated,
+    Tcache,
+    Fastbin,
+}
+
+#[derive(Clone, Copy)]
+pub struct BlockDescription {
+    // Must stay the same for as long as the block exists, so the block can
+    // be animated as it changes.
+    pub id: BlockId,
+    pub offset: i32,
+    pub size: i32,
+    pub header: i32,
+    pub used: i32,
+    pub state: BlockState,
+    pub corrupted: bool,
+    pub clobbered: i32,
+}
+
+impl BlockDescription {
+    pub fn of(block: &Block) -> Self {
+        let state = match block.cached {
+            Some(Cache::Tcache(_)) => BlockState::Tcache,
+            Some(Cache::Fastbin) => BlockState::Fastbin,
+            None if block.allocated => BlockState::Allocated,
+            None => BlockState::Free,
+        };
+        BlockDescription {
+            id: block.id,
+            offset: block.offset,
+            size: block.size,
+            header: block.header,
+            used: block.space_used,
+            state: state,
+            corrupted: block.corrupted,
+            clobbered: block.clobbered,
+        }
+    }
+
+    pub fn end(&self) -> i32 {
+        self.offset + self.size
+    }
+
+    // Whether the heap still has the block marked in use, as it does blocks
+    // waiting in a tcache or fastbin.
+    pub fn in_use(&self) -> bool {
+        match self.state {
+            BlockState::Allocated | BlockState::Tcache | BlockState::Fastbin => true,
+            BlockState::Free | BlockState::Top => false,
+        }
+    }
+
+    // What can be said about any allocator's block, without knowing where
+    // its heap starts.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("block at offset {}", self.offset),
+            format!("size {} B: header {} B", self.size, self.header),
+        ];
+        let payload = self.size - self.header;
+        lines.push(match self.state {
+            BlockState::Allocated => format!("payload {} B used, {} B padding",
+                self.used, payload - self.used),
+            BlockState::Top => format!("{} B free, at the top of the heap", payload),
+            BlockState::Tcache => format!("{} B free, in a tcache", payload),
+            BlockState::Fastbin => format!("{} B free, in a fastbin", payload),
+            BlockState::Free => format!("{} B free", payload),
+        });
+        if self.corrupted {
+            lines.push(String::from("corrupted"));
+        }
+        lines
+    }
+}
+
+// A memory allocator the tool can drive and draw. Implement this for your
+// own allocator and add it to `by_name` to run it in place of the built-in
+// heap. Errors use HeapError so bad frees are explained like any other.
+pub trait Allocator {
+    fn name(&self) -> String;
+
+    // Starts over with an empty heap at address `base` that may grow to
+    // `capacity` bytes.
+    fn init(&mut self, base: i32, capacity: i32);
+
+    fn malloc(&mut self, bytes: i32) -> Result<i32, HeapError>;
+
+    fn free(&mut self, ptr: i32) -> Result<(), HeapError>;
+
+    fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError>;
+
+    // The program moved the break by `bytes`, which is negative to give
+    // memory back. Returns the new size of the heap.
+    fn sbrk_request(&mut self, bytes: i32) -> Result<i32, HeapError>;
+
+    // Every block, free or not, in address order.
+    fn describe_blocks(&self) -> Vec<BlockDescription>;
+
+    // The block whose bytes, header included, contain `offset`.
+    fn block_at(&self, offset: i32) -> Option<BlockDescription> {
+        self.describe_blocks().into_iter()
+            .find(|block| block.offset <= offset && offset < block.end())
+    }
+
+    // A few lines about the block at `offset`, for its tooltip.
+    fn describe_block(&self, offset: i32) -> Vec<String> {
+        self.block_at(offset).map_or(vec![], |block| block.describe())
+    }
+
+    // Bytes of the address space in use, which is everything up to the end
+    // of the last block unless the allocator reserves memory it hasn't
+    // touched yet.
+    fn heap_size(&self) -> i32 {
+        self.describe_blocks().iter().map(|b| b.end()).max().unwrap_or(0)
+    }
+
+    // Each free list with a name for it, as the pointers of its chunks from
+    // head to tail, for drawing the links between them.
+    fn free_lists(&self) -> Vec<(String, Vec<i32>)> {
+        vec![]
+    }
+}
+
+// Names the `allocator` setting accepts. "builtin" is the heap the rest of
+// the tool is built around, and the only one whose blocks can be split,
+// coalesced and written to by hand. "wasm" runs a module loaded on the page,
+// which only the page itself can make, so `by_name` doesn't know it.
+pub static ALLOCATORS: [&str; 4] = ["builtin", "bump", "pages", "wasm"];
+
+pub fn by_name(name: &str, config: &Config) -> Option<Box<dyn Allocator>> {
+    match name {
+        "bump" => Some(Box::new(Bump::new())),
+        "pages" => Some(Box::new(PageHeap::new(
+            0, config.page_size.min(config.heap_capacity), 0))),
+        _ => None,
+    }
+}
+
+// Placement policies a trace can be run with, for comparing allocators.
+// Each is a heap that manages itself, or one of the allocators above.
+pub static POLICIES: [&str; 7] = [
+    "first-fit", "next-fit", "best-fit", "worst-fit", "ptmalloc", "bump", "pages",
+];
+
+pub fn policy(name: &str, config: &Config) -> Option<Box<dyn Allocator>> {
+    let fit = match name {
+        "first-fit" | "ptmalloc" => Fit::First,
+        "next-fit" => Fit::Next,
+        "best-fit" => Fit::Best,
+        "worst-fit" => Fit::Worst,
+        _ => return by_name(name, config),
+    };
+    let mut heap = Heap::with_geometry(
+        0, config.header_size, config.alignment, config.heap_capacity);
+    heap.fit = fit;
+    heap.managed = true;
+    if name == "ptmalloc" {
+        heap.bins = Some(Bins::new());
+    }
+    Some(Box::new(heap))
+}
+
+impl Allocator for Heap {
+    fn name(&self) -> String {
+        if self.bins.is_some() {
+            String::from("ptmalloc")
+        } else {
+            self.fit.to_string()
+        }
+    }
+
+    fn init(&mut self, base: i32, capacity: i32) {
+        let (ptmalloc, fit, managed) = (self.bins.is_some(), self.fit, self.managed);
+        *self = Heap::with_geometry(base, self.header, self.alignment, capacity);
+        if ptmalloc {
+            self.bins = Some(Bins::new());
+        }
+        self.fit = fit;
+        self.managed = managed;
+    }
+
+    fn malloc(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        Heap::malloc(self, bytes)
+    }
+
+    fn free(&mut self, ptr: i32) -> Result<(), HeapError> {
+        Heap::free(self, ptr).map(|_| ())
+    }
+
+    fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        Heap::realloc(self, ptr, bytes)
+    }
+
+    fn sbrk_request(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        if bytes >= 0 {
+            self.sbrk(bytes)?;
+        } else {
+            self.shrink(-bytes)?;
+        }
+        Ok(self.end_of_heap_bytes)
+    }
+
+    fn describe_blocks(&self) -> Vec<BlockDescription> {
+        let top = self.bins.as_ref().and(self.top());
+        self.blocks.iter().enumerate().map(|(i, block)| {
+            let mut description = BlockDescription::of(block);
+            if top == Some(i) {
+                description.state = BlockState::Top;
+            }
+            description
+        }).collect()
+    }
+
+    fn describe_block(&self, offset: i32) -> Vec<String> {
+        self.find_containing(self.base + offset)
+            .map_or(vec![], |i| self.blocks[i].describe())
+    }
+
+    // ptmalloc's bins, or else the free blocks in the order a fit search
+    // walks them.
+    fn free_lists(&self) -> Vec<(String, Vec<i32>)> {
+        if let Some(bins) = &self.bins {
+            return bins.lists().into_iter()
+                .map(|(name, bin)| (name, bin.iter().map(|c| c.ptr).collect()))
+                .collect();
+        }
+        let free: Vec<i32> = self.blocks.iter()
+            .filter(|b| !b.allocated && b.cached.is_none())
+            .map(|b| b.addr())
+            .collect();
+        if free.is_empty() {
+            return vec![];
+        }
+        vec![(String::from("free blocks"), free)]
+    }
+}
+
+impl Allocator for PageHeap {
+    fn name(&self) -> String {
+        String::from("pages")
+    }
+
+    fn init(&mut self, base: i32, capacity: i32) {
+        *self = PageHeap::new(base, self.page_size, capacity);
+    }
+
+    fn malloc(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        PageHeap::malloc(self, bytes).map(|(ptr, _)| ptr)
+    }
+
+    fn free(&mut self, ptr: i32) -> Result<(), HeapError> {
+        PageHeap::free(self, ptr).map(|_| ())
+    }
+
+    fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        PageHeap::realloc(self, ptr, bytes)
+    }
+
+    // Every page is there from the start, so there is nothing to grow.
+    fn sbrk_request(&mut self, _bytes: i32) -> Result<i32, HeapError> {
+        Err(HeapError::Unsupported("sbrk"))
+    }
+
+    // Slots are blocks without headers, and unused pages one free block
+    // each.
+    fn describe_blocks(&self) -> Vec<BlockDescription> {
+        let mut blocks = vec![];
+        let describe = |offset: i32, size: i32, used: Option<i32>| {
+            BlockDescription {
+                id: offset as BlockId,
+                offset: offset,
+                size: size,
+                header: 0,
+                used: used.unwrap_or(0),
+                state: if used.is_some() {
+                    BlockState::Allocated
+                } else {
+                    BlockState::Free
+                },
+                corrupted: false,
+                clobbered: 0,
+            }
+        };
+        for (page, kind) in self.pages.iter().enumerate() {
+            let start = self.page_addr(page) - self.base;
+            match kind {
+                PageKind::Unused =>
+                    blocks.push(describe(start, self.page_size, None)),
+                PageKind::Small { class, used, .. } => {
+                    for (slot, bytes) in used.iter().enumerate() {
+                        blocks.push(describe(
+                            start + slot as i32 * class, *class, *bytes));
+                    }
+                }
+                PageKind::Large { pages, used } => blocks.push(describe(
+                    start, *pages as i32 * self.page_size, Some(*used))),
+                PageKind::Continuation => {}
+            }
+        }
+        blocks
+    }
+
+    fn describe_block(&self, offset: i32) -> Vec<String> {
+        if offset < 0 {
+            return vec![];
+        }
+        let page = (offset / self.page_size) as usize;
+        let slot = match self.pages.get(page) {
+            Some(PageKind::Small { class, .. }) => (offset % self.page_size / class) as usize,
+            Some(_) => 0,
+            None => return vec![],
+        };
+        self.describe(page, slot)
+    }
+
+    // Pages nothing has been put in yet are only reserved.
+    fn heap_size(&self) -> i32 {
+        let touched = self.pages.iter()
+            .rposition(|kind| !matches!(kind, PageKind::Unused));
+        match touched {
+            Some(page) => self.page_addr(page + 1) - self.base,
+            None => 0,
+        }
+    }
+}
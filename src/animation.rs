@@ -0,0 +1,122 @@
+use malloc_vis::allocator::BlockDescription;
+use malloc_vis::heap::BlockId;
+use crate::view::View;
+
+use std::collections::HashMap;
+
+use quicksilver::geom::Rectangle;
+
+// Fraction of the remaining distance covered per second at speed 1.
+static EASE_PER_SEC: f32 = 8.0;
+
+// Where a block is drawn, which trails where it really is while an
+// operation is being animated.
+#[derive(Clone, Copy)]
+pub struct Geometry {
+    pub offset: f32,
+    pub size: f32,
+    pub header: f32,
+    pub used: f32,
+}
+
+impl Geometry {
+    pub fn of(block: &BlockDescription) -> Self {
+        Geometry {
+            offset: block.offset as f32,
+            size: block.size as f32,
+            header: block.header as f32,
+            used: block.used as f32,
+        }
+    }
+
+    // Everything after the header.
+    pub fn payload_rects(&self, view: &View) -> Vec<Rectangle> {
+        let header = self.header.min(self.size);
+        view.segments(self.offset + header, self.size - header)
+    }
+
+    pub fn header_rects(&self, view: &View) -> Vec<Rectangle> {
+        view.segments(self.offset, self.header.min(self.size))
+    }
+
+    pub fn used_rects(&self, view: &View) -> Vec<Rectangle> {
+        let header = self.header.min(self.size);
+        view.segments(
+            self.offset + header, self.used.min(self.size - header))
+    }
+
+    // The block as a whole, header included.
+    pub fn rects(&self, view: &View) -> Vec<Rectangle> {
+        view.segments(self.offset, self.size)
+    }
+
+    // Moves `fraction` of the way towards `target`.
+    fn ease(&mut self, target: &Geometry, fraction: f32) {
+        approach(&mut self.offset, target.offset, fraction);
+        approach(&mut self.size, target.size, fraction);
+        approach(&mut self.used, target.used, fraction);
+        self.header = target.header;
+    }
+}
+
+fn approach(value: &mut f32, goal: f32, fraction: f32) {
+    *value += (goal - *value) * fraction;
+    if (goal - *value).abs() < 0.5 {
+        *value = goal;
+    }
+}
+
+// Eases blocks from where they were drawn towards where the allocator says
+// they are. New blocks grow out of the point they were carved from: the end of the
+// heap after an sbrk, or the end of their parent after a split.
+pub struct Animator {
+    shown: HashMap<BlockId, Geometry>,
+    last_end: i32,
+    pub speed: f32,
+    pub paused: bool,
+}
+
+impl Animator {
+    pub fn new(speed: f32) -> Self {
+        Animator {
+            shown: HashMap::new(),
+            last_end: 0,
+            speed: speed,
+            paused: false,
+        }
+    }
+
+    // `end` is the size of the heap the blocks are in.
+    pub fn update(
+            &mut self, blocks: &[BlockDescription], end: i32, seconds: f32) {
+        if self.paused {
+            return;
+        }
+
+        let fraction = if self.speed <= 0.0 {
+            1.0
+        } else {
+            (seconds * EASE_PER_SEC * self.speed).min(1.0)
+        };
+        let last_end = self.last_end;
+        self.shown.retain(|id, _| blocks.iter().any(|b| b.id == *id));
+        for block in blocks.iter() {
+            let target = Geometry::of(block);
+            let shown = self.shown.entry(block.id).or_insert_with(|| {
+                let start = if block.offset >= last_end {
+                    target.offset
+                } else {
+                    target.offset + target.size
+                };
+                Geometry { offset: start, size: 0.0, used: 0.0, ..target }
+            });
+            shown.ease(&target, fraction);
+        }
+        self.last_end = end;
+    }
+
+    pub fn shown(&self, block: &BlockDescription) -> Geometry {
+        self.shown.get(&block.id).cloned()
+            .unwrap_or_else(|| Geometry::of(block))
+    }
+}
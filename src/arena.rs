@@ -0,0 +1,351 @@
+use malloc_vis::allocator::{policy, Allocator, BlockDescription, BlockState};
+use malloc_vis::bins::Bins;
+use malloc_vis::checker::{check_heap, Violation};
+use malloc_vis::config::Config;
+use malloc_vis::constants::HEAP_BASE;
+use malloc_vis::heap::{BlockId, Heap, HeapError};
+use crate::animation::{Animator, Geometry};
+use crate::objects::{DragHandle, BUTTON_TEXT, STAT_TEXT};
+use crate::ruler::{draw_ruler, hex, LABEL_SIZE};
+use crate::text::Text;
+use crate::view::View;
+#[cfg(target_arch = "wasm32")]
+use crate::wasm::WasmAllocator;
+
+use quicksilver::{
+    Result,
+    geom::{Shape, Rectangle, Vector},
+    graphics::{Background::Col, Color},
+    lifecycle::Window
+};
+
+// Arenas live this far apart in the address space, like glibc's 64 MiB
+// heaps for non-main arenas but smaller so addresses stay readable.
+static ARENA_STRIDE: i32 = 0x100000;
+
+// Space between stacked arenas.
+static ARENA_GAP: f32 = 20.0;
+
+// Height of all the arenas stacked together.
+pub fn stack_height(config: &Config) -> f32 {
+    let arenas = config.arenas as f32;
+    arenas * (View::new(config).area_height() + ARENA_GAP) - ARENA_GAP
+}
+
+// The four edges of `rect`, `width` thick and drawn inside it.
+fn outline(rect: &Rectangle, width: f32) -> [Rectangle; 4] {
+    [
+        Rectangle::new(rect.pos, (rect.width(), width)),
+        Rectangle::new(
+            (rect.x(), rect.y() + rect.height() - width), (rect.width(), width)),
+        Rectangle::new(rect.pos, (width, rect.height())),
+        Rectangle::new(
+            (rect.x() + rect.width() - width, rect.y()), (width, rect.height())),
+    ]
+}
+
+// One heap with everything needed to draw and grow it. glibc gives threads
+// their own arenas so they don't contend on a single heap lock.
+pub struct Arena {
+    pub heap: Heap,
+    // An allocator picked with the `allocator` setting, which runs in place
+    // of the heap above. The heap is then left empty.
+    pub plugin: Option<Box<dyn Allocator>>,
+    pub view: View,
+    pub sbrk_obj: DragHandle,
+    pub animator: Animator,
+    // What the heap checker found after the last operation.
+    pub violations: Vec<Violation>,
+    // Payload offsets of blocks that the other policies of a comparison
+    // placed somewhere else.
+    pub diverged: Vec<i32>,
+}
+
+impl Arena {
+    pub fn new(index: usize, config: &Config) -> Self {
+        let stride = (config.heap_capacity + ARENA_STRIDE - 1)
+            / ARENA_STRIDE * ARENA_STRIDE;
+        let mut view = View::new(config);
+        view.top = index as f32 * (view.area_height() + ARENA_GAP);
+        let base = HEAP_BASE + index as i32 * stride;
+        let mut heap = Heap::with_geometry(
+            base, config.header_size, config.alignment, config.heap_capacity);
+        if config.ptmalloc {
+            heap.bins = Some(Bins::new());
+        }
+        let name = config.compared().get(index).cloned().unwrap_or(&config.allocator);
+        let plugin = match name {
+            #[cfg(target_arch = "wasm32")]
+            "wasm" => Some(Box::new(WasmAllocator::new()) as Box<dyn Allocator>),
+            _ => policy(name, config),
+        };
+        let plugin = plugin.map(|mut plugin| {
+            plugin.init(base, config.heap_capacity);
+            plugin
+        });
+        Arena {
+            heap: heap,
+            plugin: plugin,
+            view: view,
+            sbrk_obj: DragHandle::new(0.0),
+            animator: Animator::new(config.anim_speed),
+            violations: vec![],
+            diverged: vec![],
+        }
+    }
+
+    pub fn name(index: usize) -> String {
+        if index == 0 {
+            String::from("main arena")
+        } else {
+            format!("arena {}", index)
+        }
+    }
+
+    pub fn allocator(&self) -> &dyn Allocator {
+        match &self.plugin {
+            Some(plugin) => plugin.as_ref(),
+            None => &self.heap,
+        }
+    }
+
+    pub fn allocator_mut(&mut self) -> &mut dyn Allocator {
+        match &mut self.plugin {
+            Some(plugin) => plugin.as_mut(),
+            None => &mut self.heap,
+        }
+    }
+
+    // Size of the heap, going by its blocks.
+    pub fn end(&self) -> i32 {
+        self.allocator().describe_blocks().iter()
+            .map(|block| block.end())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Moves the break by `bytes` and puts the sbrk handle at the new end.
+    pub fn sbrk(&mut self, bytes: i32) -> std::result::Result<i32, HeapError> {
+        let result = self.allocator_mut().sbrk_request(bytes);
+        self.sbrk_obj.handle_bytes = self.end() as f32;
+        result
+    }
+
+    pub fn update(&mut self, seconds: f32) {
+        let blocks = self.allocator().describe_blocks();
+        let end = self.end();
+        self.animator.update(&blocks, end, seconds);
+    }
+
+    // Runs the heap checker over the built-in heap. Only needed after
+    // something has changed it.
+    pub fn check(&mut self) {
+        if self.plugin.is_none() {
+            self.violations = check_heap(&self.heap);
+        }
+    }
+
+    // What the checker found wrong with the block `id`.
+    pub fn violations_at(&self, id: BlockId) -> Vec<String> {
+        self.violations.iter()
+            .filter(|v| v.block == Some(id))
+            .map(|v| v.message.clone())
+            .collect()
+    }
+
+    // The block `id`, as whichever allocator runs the arena describes it.
+    pub fn block(&self, id: BlockId) -> Option<BlockDescription> {
+        self.allocator().describe_blocks().into_iter().find(|block| block.id == id)
+    }
+
+    // The block under `pos`.
+    pub fn block_at(&self, pos: Vector) -> Option<BlockDescription> {
+        self.allocator().describe_blocks().into_iter()
+            .find(|block| Geometry::of(block).payload_rects(&self.view).iter()
+                .any(|rect| pos.overlaps_rectangle(rect)))
+    }
+
+    // The pointer malloc handed out for `block`.
+    pub fn ptr(&self, block: &BlockDescription) -> i32 {
+        self.heap.base + block.offset + block.header
+    }
+
+    // Shades the address space of a wrapped heap and labels each row with
+    // the address it starts at.
+    fn draw_rows(&self, window: &mut Window, text: &mut Text) -> Result<()> {
+        let row_width = self.view.width(self.view.row_bytes as f32);
+        for (bytes, start) in self.view.rows() {
+            window.draw(
+                &Rectangle::new(start, (row_width, self.view.height)),
+                Col(Color::BLACK.with_alpha(0.05)));
+            text.draw(
+                window,
+                &hex(self.heap.base + bytes),
+                Vector::new(5, start.y),
+                STAT_TEXT,
+                Color::BLACK)?;
+        }
+        Ok(())
+    }
+
+    fn draw_sbrk(&self, window: &mut Window, text: &mut Text) -> Result<()> {
+        let sbrk_rect = self.sbrk_obj.rect(&self.view);
+        window.draw(&sbrk_rect, Col(Color::CYAN));
+        text.draw_centered(
+            window, "SBRK", sbrk_rect.center(), BUTTON_TEXT, Color::BLACK)?;
+
+        if self.sbrk_obj.selected {
+            let selected_overlay = Color::BLACK.with_alpha(0.25);
+            window.draw(&sbrk_rect, Col(selected_overlay));
+
+            let curr_bytes = self.sbrk_obj.handle_bytes as i32;
+            let new_bytes = curr_bytes - self.end();
+
+            text.draw(
+                window,
+                &format!("{:+} B", new_bytes),
+                Vector::new(
+                    sbrk_rect.x(),
+                    sbrk_rect.y() + sbrk_rect.height() + 1.0
+                ),
+                STAT_TEXT,
+                Color::BLACK)?;
+        }
+        Ok(())
+    }
+
+    pub fn draw(
+            &self,
+            window: &mut Window,
+            text: &mut Text,
+            config: &Config,
+            label: &str) -> Result<()> {
+        self.draw_rows(window, text)?;
+        draw_ruler(window, text, &self.view, self.heap.base)?;
+        self.draw_sbrk(window, text)?;
+
+        let label_x = window.screen_size().x - Text::width(label, STAT_TEXT) - 5.0;
+        text.draw(
+            window, label, Vector::new(label_x, self.view.top),
+            STAT_TEXT, Color::BLACK)?;
+        if !self.violations.is_empty() {
+            let problems = match self.violations.len() {
+                1 => String::from("heap check: 1 problem"),
+                n => format!("heap check: {} problems", n),
+            };
+            let x = window.screen_size().x - Text::width(&problems, STAT_TEXT) - 5.0;
+            text.draw(
+                window, &problems, Vector::new(x, self.view.top + STAT_TEXT),
+                STAT_TEXT, Color::RED)?;
+        }
+
+        let fill_color = Color::WHITE
+            .with_red(244.0/256.0)
+            .with_blue(113.0/256.0)
+            .with_green(66.0/256.0);
+        let base = self.heap.base;
+        for alloc in self.allocator().describe_blocks().iter() {
+            let shown = self.animator.shown(alloc);
+            let rects = shown.payload_rects(&self.view);
+            if rects.is_empty() {
+                continue;
+            }
+            let cached = match alloc.state {
+                BlockState::Tcache => Some(Color::GREEN),
+                BlockState::Fastbin => Some(Color::GREEN.with_green(0.5)),
+                _ => None,
+            };
+            if let Some(color) = cached {
+                for rect in rects.iter() {
+                    window.draw(rect, Col(color));
+                }
+            } else if alloc.state == BlockState::Allocated {
+                for rect in rects.iter() {
+                    window.draw(rect, Col(Color::RED));
+                }
+                for rect in shown.used_rects(&self.view).iter() {
+                    window.draw(rect, Col(fill_color));
+                }
+
+                // Label the block with the pointer malloc returned for it.
+                let ptr = base + alloc.offset + alloc.header;
+                let label = hex(ptr);
+                let first = rects[0];
+                if Text::width(&label, STAT_TEXT) <= first.width() {
+                    text.draw(
+                        window,
+                        &label,
+                        Vector::new(first.x(), first.y()),
+                        STAT_TEXT,
+                        Color::BLACK)?;
+                }
+            } else {
+                for rect in rects.iter() {
+                    window.draw(rect, Col(Color::BLUE));
+                }
+                let top = alloc.state == BlockState::Top;
+                if top && Text::width("top", STAT_TEXT) <= rects[0].width() {
+                    text.draw(
+                        window, "top", rects[0].pos, STAT_TEXT, Color::WHITE)?;
+                }
+            }
+
+            if !config.render_gaps {
+                let header = Color::BLACK.with_alpha(0.5);
+                for rect in shown.header_rects(&self.view).iter() {
+                    window.draw(rect, Col(header));
+                }
+            }
+
+            if alloc.clobbered > 0 {
+                let clobbered = self.view.segments(
+                    shown.offset, (alloc.clobbered as f32).min(shown.size));
+                for rect in clobbered.iter() {
+                    window.draw(rect, Col(Color::YELLOW.with_alpha(0.8)));
+                }
+            }
+
+            if alloc.corrupted {
+                for rect in rects.iter() {
+                    let stripe = Rectangle::new(
+                        (rect.x(), rect.y() + rect.height() - 10.0),
+                        (rect.width(), 10));
+                    window.draw(&stripe, Col(Color::MAGENTA));
+                }
+            }
+
+            // Outline blocks the heap checker found something wrong with.
+            if self.violations.iter().any(|v| v.block == Some(alloc.id)) {
+                for rect in shown.rects(&self.view).iter() {
+                    for edge in outline(rect, 3.0).iter() {
+                        window.draw(edge, Col(Color::ORANGE));
+                    }
+                }
+            }
+
+            if self.diverged.contains(&(alloc.offset + alloc.header)) {
+                for rect in shown.rects(&self.view).iter() {
+                    for edge in outline(rect, 2.0).iter() {
+                        window.draw(edge, Col(Color::PURPLE));
+                    }
+                }
+            }
+
+            // Label where the block starts, header included, above the
+            // corruption stripe.
+            let start = hex(base + alloc.offset);
+            let whole = shown.rects(&self.view)[0];
+            if Text::width(&start, LABEL_SIZE) + 4.0 <= whole.width() {
+                text.draw(
+                    window,
+                    &start,
+                    Vector::new(
+                        whole.x() + 2.0,
+                        whole.y() + whole.height() - 26.0),
+                    LABEL_SIZE,
+                    Color::WHITE)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+// Runs a malloclab trace against one of the allocation policies without
+// opening a window, and prints what each op did and how it went overall:
+//
+//     malloc-sim traces/binary.rep --policy best-fit header=8 align=8
+//
+// Settings are the same keys the page takes. Pass --json for output that
+// scripts can read. In place of a trace file, --workload generates one, e.g.
+// `--workload fifo,exp:64,n=500`, and --emit prints it instead of running it.
+// --svg FILE draws the heap as the trace left it.
+use std::env;
+use std::fs;
+use std::process;
+
+use malloc_vis::allocator::{policy, POLICIES};
+use malloc_vis::config::Config;
+use malloc_vis::constants::HEAP_BASE;
+use malloc_vis::metrics::Metrics;
+use malloc_vis::svg::Frame;
+use malloc_vis::trace::{Trace, TraceRunner};
+use malloc_vis::workload::Workload;
+
+// How much more than it needs a failed malloc sbrks, as CHUNKSIZE in the
+// malloclab handout.
+static GROW: i32 = 4096;
+
+fn usage() -> ! {
+    eprintln!(
+        concat!("usage: malloc-sim (TRACE | --workload SPEC [--emit])",
+            " [--policy {}] [--json] [--svg FILE] [key=value ...]"),
+        POLICIES.join("|"));
+    process::exit(2);
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn main() {
+    let mut trace_path = None;
+    let mut policy_name = String::from("first-fit");
+    let mut json = false;
+    let mut workload = None;
+    let mut emit = false;
+    let mut svg_path = None;
+    let mut config = Config {
+        // Traces are much bigger than what fits on screen.
+        heap_capacity: 1 << 24,
+        ..Config::default()
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--policy" => policy_name = args.next().unwrap_or_else(|| usage()),
+            "--json" => json = true,
+            "--workload" => workload = Some(args.next().unwrap_or_else(|| usage())),
+            "--emit" => emit = true,
+            "--svg" => svg_path = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if arg.contains('=') => {
+                if let Err(err) = config.parse(&arg, '&') {
+                    eprintln!("{}", err);
+                    process::exit(2);
+                }
+            }
+            _ if trace_path.is_none() => trace_path = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let trace = match (&workload, &trace_path) {
+        (Some(spec), None) => Workload::parse(spec).map(|w| w.generate()),
+        (None, Some(path)) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Can't read {}: {}", path, err);
+                process::exit(1);
+            });
+            Trace::parse(&text)
+        }
+        _ => usage(),
+    };
+    let trace = trace.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if emit {
+        print!("{}", trace);
+        return;
+    }
+
+    if policy_name == "wasm" {
+        eprintln!("wasm allocators only run in the browser.");
+        process::exit(2);
+    }
+    let mut allocator = policy(&policy_name, &config).unwrap_or_else(|| {
+        eprintln!("Unknown policy {:?}", policy_name);
+        usage()
+    });
+
+    allocator.init(HEAP_BASE, config.heap_capacity);
+    let mut runner = TraceRunner::new(trace);
+    runner.grow = GROW;
+    let mut metrics = Metrics::new();
+    let mut results = vec![];
+    while let Some((op, result)) = runner.step(allocator.as_mut()) {
+        metrics.record(allocator.as_ref(), result.is_ok());
+        let n = metrics.ops;
+        results.push(match (json, result) {
+            (false, Ok(Some(ptr))) => format!("{:>6}  {:<20} 0x{:x}", n, op.to_string(), ptr),
+            (false, Ok(None)) => format!("{:>6}  {}", n, op),
+            (false, Err(err)) => format!("{:>6}  {:<20} failed: {}", n, op.to_string(), err),
+            (true, Ok(ptr)) => format!(
+                "{{\"op\": {}, \"ptr\": {}}}",
+                json_string(&op.to_string()),
+                ptr.map_or(String::from("null"), |p| p.to_string())),
+            (true, Err(err)) => format!(
+                "{{\"op\": {}, \"error\": {}}}",
+                json_string(&op.to_string()),
+                json_string(&err.to_string())),
+        });
+    }
+
+    let name = allocator.name();
+    if json {
+        println!("{{");
+        println!("  \"policy\": {},", json_string(&name));
+        println!("  \"ops\": [\n    {}\n  ],", results.join(",\n    "));
+        println!("  \"metrics\": {{");
+        println!("    \"ops\": {},", metrics.ops);
+        println!("    \"failures\": {},", metrics.failures);
+        println!("    \"utilization\": {:.4},", metrics.utilization());
+        println!("    \"peak_payload\": {},", metrics.peak_payload);
+        println!("    \"peak_heap\": {},", metrics.peak_heap);
+        println!("    \"fragmentation\": {:.4}", metrics.fragmentation());
+        println!("  }}");
+        println!("}}");
+    } else {
+        for line in results.iter() {
+            println!("{}", line);
+        }
+        println!();
+        println!("policy         {}", name);
+        println!("ops            {}", metrics.ops);
+        println!("failures       {}", metrics.failures);
+        println!("utilization    {:.1}%", metrics.utilization() * 100.0);
+        println!("peak payload   {} B", metrics.peak_payload);
+        println!("peak heap      {} B", metrics.peak_heap);
+        println!("fragmentation  {:.1}%", metrics.fragmentation() * 100.0);
+    }
+
+    if let Some(path) = svg_path {
+        let source = workload.or(trace_path).unwrap_or_default();
+        let title = format!("{} after {}", name, source);
+        let mut frame = Frame::of(allocator.as_ref(), HEAP_BASE, &title);
+        frame.stats.push(format!(
+            "utilization {:.1}%, peak heap {} B, {} failed",
+            metrics.utilization() * 100.0, metrics.peak_heap, metrics.failures));
+        let svg = frame.render(config.window_width as f32, config.row_bytes);
+        if let Err(err) = fs::write(&path, svg) {
+            eprintln!("Can't write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
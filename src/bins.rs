@@ -0,0 +1,307 @@
+use crate::heap::{Block, Cache};
+
+use std::collections::BTreeMap;
+
+// Largest chunk that goes into a fastbin, glibc's default global_max_fast on
+// 64-bit.
+pub static FASTBIN_MAX: i32 = 128;
+// Chunks this big or bigger go into largebins.
+pub static LARGEBIN_MIN: i32 = 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Chunk {
+    pub ptr: i32,
+    pub size: i32,
+}
+
+// glibc's largebin_index_64: bins get wider the larger the chunks they hold.
+pub fn large_index(size: i32) -> usize {
+    let size = size as usize;
+    if size >> 6 <= 48 {
+        48 + (size >> 6)
+    } else if size >> 9 <= 20 {
+        91 + (size >> 9)
+    } else if size >> 12 <= 10 {
+        110 + (size >> 12)
+    } else if size >> 15 <= 4 {
+        119 + (size >> 15)
+    } else if size >> 18 <= 2 {
+        124 + (size >> 18)
+    } else {
+        126
+    }
+}
+
+// The free lists of a ptmalloc arena. Every list keeps its head, where new
+// chunks are linked in, at index 0, so a chunk's fd is the entry after it
+// and its bk the entry before it. Fastbins are LIFO, while the unsorted and
+// small bins are taken from the tail, oldest first. Largebins hold a range
+// of sizes and are kept sorted, largest first.
+pub struct Bins {
+    pub fast: BTreeMap<i32, Vec<Chunk>>,
+    pub unsorted: Vec<Chunk>,
+    pub small: BTreeMap<i32, Vec<Chunk>>,
+    pub large: BTreeMap<usize, Vec<Chunk>>,
+}
+
+fn take_tail<K: Ord + Copy>(
+        bins: &mut BTreeMap<K, Vec<Chunk>>, key: K) -> Option<Chunk> {
+    let bin = bins.get_mut(&key)?;
+    let chunk = bin.pop();
+    if bin.is_empty() {
+        bins.remove(&key);
+    }
+    chunk
+}
+
+fn remove_from<K: Ord + Copy>(
+        bins: &mut BTreeMap<K, Vec<Chunk>>, ptr: i32) -> Option<Chunk> {
+    let (key, i) = bins.iter()
+        .filter_map(|(key, bin)| {
+            bin.iter().position(|c| c.ptr == ptr).map(|i| (*key, i))
+        })
+        .next()?;
+    let bin = bins.get_mut(&key)?;
+    let chunk = bin.remove(i);
+    if bin.is_empty() {
+        bins.remove(&key);
+    }
+    Some(chunk)
+}
+
+fn retain_in<K: Ord + Copy, F: Fn(&Chunk) -> bool>(
+        bins: &mut BTreeMap<K, Vec<Chunk>>, keep: F) {
+    for bin in bins.values_mut() {
+        bin.retain(|c| keep(c));
+    }
+    let empty: Vec<K> = bins.iter()
+        .filter(|(_, bin)| bin.is_empty())
+        .map(|(key, _)| *key)
+        .collect();
+    for key in empty {
+        bins.remove(&key);
+    }
+}
+
+impl Default for Bins {
+    fn default() -> Self {
+        Bins::new()
+    }
+}
+
+impl Bins {
+    pub fn new() -> Self {
+        Bins {
+            fast: BTreeMap::new(),
+            unsorted: vec![],
+            small: BTreeMap::new(),
+            large: BTreeMap::new(),
+        }
+    }
+
+    pub fn contains(&self, ptr: i32) -> bool {
+        self.lists().iter().any(|(_, bin)| bin.iter().any(|c| c.ptr == ptr))
+    }
+
+    // Unlinks `ptr` from whichever list it is in.
+    pub fn remove(&mut self, ptr: i32) -> Option<Chunk> {
+        if let Some(i) = self.unsorted.iter().position(|c| c.ptr == ptr) {
+            return Some(self.unsorted.remove(i));
+        }
+        remove_from(&mut self.fast, ptr)
+            .or_else(|| remove_from(&mut self.small, ptr))
+            .or_else(|| remove_from(&mut self.large, ptr))
+    }
+
+    pub fn push_fast(&mut self, chunk: Chunk) {
+        self.fast.entry(chunk.size).or_default().insert(0, chunk);
+    }
+
+    pub fn take_fast(&mut self, size: i32) -> Option<Chunk> {
+        let bin = self.fast.get_mut(&size)?;
+        let chunk = bin.remove(0);
+        if bin.is_empty() {
+            self.fast.remove(&size);
+        }
+        Some(chunk)
+    }
+
+    // Empties every fastbin, for malloc_consolidate.
+    pub fn drain_fast(&mut self) -> Vec<Chunk> {
+        let fast = std::mem::take(&mut self.fast);
+        fast.into_values().flatten().collect()
+    }
+
+    pub fn push_unsorted(&mut self, chunk: Chunk) {
+        self.unsorted.insert(0, chunk);
+    }
+
+    pub fn pop_unsorted(&mut self) -> Option<Chunk> {
+        self.unsorted.pop()
+    }
+
+    // Files a chunk from the unsorted bin into its small or large bin.
+    pub fn sort(&mut self, chunk: Chunk) {
+        if chunk.size < LARGEBIN_MIN {
+            self.small.entry(chunk.size).or_default()
+                .insert(0, chunk);
+            return;
+        }
+
+        let bin = self.large.entry(large_index(chunk.size))
+            .or_default();
+        let i = bin.iter().position(|c| c.size < chunk.size)
+            .unwrap_or(bin.len());
+        bin.insert(i, chunk);
+    }
+
+    pub fn take_small(&mut self, size: i32) -> Option<Chunk> {
+        take_tail(&mut self.small, size)
+    }
+
+    // Takes the smallest chunk in the small and large bins that can hold
+    // `size` bytes.
+    pub fn take_best(&mut self, size: i32) -> Option<Chunk> {
+        let best = self.small.values().chain(self.large.values())
+            .flat_map(|bin| bin.iter().rev())
+            .filter(|c| c.size >= size)
+            .min_by_key(|c| c.size)
+            .cloned()?;
+        self.remove(best.ptr)
+    }
+
+    // The chunks a chunk's fd and bk point to, 0 at either end of a list.
+    // Fastbins are singly linked and have no bk.
+    pub fn links(&self, ptr: i32) -> Option<(i32, Option<i32>)> {
+        let ptr_at = |bin: &Vec<Chunk>, i: Option<usize>| {
+            i.and_then(|i| bin.get(i)).map_or(0, |c| c.ptr)
+        };
+        for bin in self.fast.values() {
+            if let Some(i) = bin.iter().position(|c| c.ptr == ptr) {
+                return Some((ptr_at(bin, Some(i + 1)), None));
+            }
+        }
+        let doubly = Some(&self.unsorted).into_iter()
+            .chain(self.small.values())
+            .chain(self.large.values());
+        for bin in doubly {
+            if let Some(i) = bin.iter().position(|c| c.ptr == ptr) {
+                let bk = ptr_at(bin, i.checked_sub(1));
+                return Some((ptr_at(bin, Some(i + 1)), Some(bk)));
+            }
+        }
+        None
+    }
+
+    // Every non-empty list with a name for it, in the order glibc searches
+    // them.
+    pub fn lists(&self) -> Vec<(String, &Vec<Chunk>)> {
+        let mut lists = vec![];
+        for (size, bin) in self.fast.iter() {
+            lists.push((format!("fastbin {}", size), bin));
+        }
+        if !self.unsorted.is_empty() {
+            lists.push((String::from("unsorted"), &self.unsorted));
+        }
+        for (size, bin) in self.small.iter() {
+            lists.push((format!("smallbin {}", size), bin));
+        }
+        for (index, bin) in self.large.iter() {
+            lists.push((format!("largebin #{}", index), bin));
+        }
+        lists
+    }
+
+    // Brings the lists back in line with the blocks after the heap was
+    // changed by hand: chunks that are gone, in use or resized are dropped,
+    // and free blocks that no list knows about go into the unsorted bin.
+    pub fn sync(&mut self, blocks: &[Block]) {
+        let top = blocks.last().filter(|b| !b.allocated).map(|b| b.addr());
+        let find = |c: &Chunk| {
+            blocks.iter().find(|b| b.addr() == c.ptr && b.size == c.size)
+        };
+        retain_in(&mut self.fast, |c| {
+            find(c).is_some_and(|b| b.cached == Some(Cache::Fastbin))
+        });
+        let is_free = |c: &Chunk| {
+            find(c).is_some_and(|b| !b.allocated && Some(c.ptr) != top)
+        };
+        self.unsorted.retain(|c| is_free(c));
+        retain_in(&mut self.small, is_free);
+        retain_in(&mut self.large, is_free);
+
+        for block in blocks.iter() {
+            let chunk = Chunk { ptr: block.addr(), size: block.size };
+            if self.contains(chunk.ptr) {
+                continue;
+            }
+            if block.cached == Some(Cache::Fastbin) {
+                self.push_fast(chunk);
+            } else if !block.allocated && Some(chunk.ptr) != top {
+                self.push_unsorted(chunk);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(ptr: i32, size: i32) -> Chunk {
+        Chunk { ptr: ptr, size: size }
+    }
+
+    #[test]
+    fn fastbins_are_lifo() {
+        let mut bins = Bins::new();
+        bins.push_fast(chunk(0x1010, 32));
+        bins.push_fast(chunk(0x1030, 32));
+        bins.push_fast(chunk(0x1050, 48));
+        assert_eq!(bins.links(0x1030), Some((0x1010, None)));
+        assert_eq!(bins.take_fast(32).map(|c| c.ptr), Some(0x1030));
+        assert_eq!(bins.take_fast(32).map(|c| c.ptr), Some(0x1010));
+        assert!(bins.take_fast(32).is_none());
+        assert_eq!(bins.take_fast(48).map(|c| c.ptr), Some(0x1050));
+    }
+
+    #[test]
+    fn unsorted_chunks_are_sorted_by_size() {
+        let mut bins = Bins::new();
+        bins.push_unsorted(chunk(0x1010, 64));
+        bins.push_unsorted(chunk(0x2010, 1032));
+        bins.push_unsorted(chunk(0x3010, 1040));
+        // Oldest first.
+        while let Some(c) = bins.pop_unsorted() {
+            bins.sort(c);
+        }
+        assert!(bins.unsorted.is_empty());
+        assert_eq!(bins.small.get(&64).map(|bin| bin.len()), Some(1));
+        // Both fall in the same largebin, largest first.
+        let large: Vec<i32> = bins.large[&large_index(1032)].iter()
+            .map(|c| c.size)
+            .collect();
+        assert_eq!(large, vec![1040, 1032]);
+    }
+
+    #[test]
+    fn take_best_picks_the_smallest_fit() {
+        let mut bins = Bins::new();
+        bins.sort(chunk(0x1010, 64));
+        bins.sort(chunk(0x2010, 256));
+        bins.sort(chunk(0x3010, 2048));
+        assert_eq!(bins.take_best(100).map(|c| c.ptr), Some(0x2010));
+        assert_eq!(bins.take_best(100).map(|c| c.ptr), Some(0x3010));
+        assert!(bins.take_best(100).is_none());
+        assert!(bins.contains(0x1010));
+    }
+
+    #[test]
+    fn drain_fast_empties_every_fastbin() {
+        let mut bins = Bins::new();
+        bins.push_fast(chunk(0x1010, 32));
+        bins.push_fast(chunk(0x1050, 48));
+        assert_eq!(bins.drain_fast().len(), 2);
+        assert!(bins.fast.is_empty());
+    }
+}
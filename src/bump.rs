@@ -0,0 +1,257 @@
+use crate::allocator::{Allocator, BlockDescription, BlockState};
+use crate::heap::{BlockId, HeapError};
+
+static ALIGNMENT: i32 = 8;
+
+struct Allocation {
+    id: BlockId,
+    offset: i32,
+    size: i32,
+    used: i32,
+    freed: bool,
+}
+
+// About the simplest allocator there is, and a starting point for writing
+// your own: every malloc takes the bytes after the previous one, and freed
+// memory only comes back once everything has been freed.
+pub struct Bump {
+    base: i32,
+    capacity: i32,
+    // Size of the heap, as grown by sbrk.
+    end: i32,
+    // Where the next allocation starts.
+    next: i32,
+    next_id: BlockId,
+    allocations: Vec<Allocation>,
+}
+
+impl Bump {
+    pub fn new() -> Self {
+        Bump {
+            base: 0,
+            capacity: 0,
+            end: 0,
+            next: 0,
+            next_id: 0,
+            allocations: vec![],
+        }
+    }
+
+    // None for sizes too big to round up.
+    fn align(bytes: i32) -> Option<i32> {
+        Some(bytes.checked_add(ALIGNMENT - 1)? / ALIGNMENT * ALIGNMENT)
+    }
+
+    fn find(&self, ptr: i32) -> Result<usize, HeapError> {
+        let offset = ptr - self.base;
+        if offset < 0 || offset >= self.end {
+            return Err(HeapError::OutOfHeap(ptr));
+        }
+        match self.allocations.iter()
+                .position(|a| a.offset <= offset && offset < a.offset + a.size) {
+            Some(i) if self.allocations[i].offset != offset =>
+                Err(HeapError::InteriorPointer(ptr, self.base + self.allocations[i].offset)),
+            Some(i) if self.allocations[i].freed => Err(HeapError::DoubleFree(ptr)),
+            Some(i) => Ok(i),
+            None => Err(HeapError::InvalidPointer(ptr)),
+        }
+    }
+}
+
+impl Default for Bump {
+    fn default() -> Self {
+        Bump::new()
+    }
+}
+
+impl Allocator for Bump {
+    fn name(&self) -> String {
+        String::from("bump")
+    }
+
+    fn init(&mut self, base: i32, capacity: i32) {
+        *self = Bump::new();
+        self.base = base;
+        self.capacity = capacity;
+    }
+
+    fn malloc(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        let size = Bump::align(bytes.max(1)).ok_or(HeapError::OutOfMemory(bytes))?;
+        if size > self.end - self.next {
+            return Err(HeapError::OutOfMemory(bytes));
+        }
+
+        self.allocations.push(Allocation {
+            id: self.next_id,
+            offset: self.next,
+            size: size,
+            used: bytes,
+            freed: false,
+        });
+        self.next_id += 1;
+        self.next += size;
+        Ok(self.base + self.next - size)
+    }
+
+    fn free(&mut self, ptr: i32) -> Result<(), HeapError> {
+        let i = self.find(ptr)?;
+        self.allocations[i].freed = true;
+        if self.allocations.iter().all(|a| a.freed) {
+            self.allocations.clear();
+            self.next = 0;
+        }
+        Ok(())
+    }
+
+    // Only the last allocation can grow in place.
+    fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        let i = self.find(ptr)?;
+        let last = i + 1 == self.allocations.len();
+        let allocation = &mut self.allocations[i];
+        if bytes <= allocation.size {
+            allocation.used = bytes;
+            return Ok(ptr);
+        }
+        let size = Bump::align(bytes).ok_or(HeapError::OutOfMemory(bytes))?;
+        if last && size <= self.end - allocation.offset {
+            allocation.size = size;
+            allocation.used = bytes;
+            self.next = allocation.offset + size;
+            return Ok(ptr);
+        }
+
+        let new_ptr = self.malloc(bytes)?;
+        self.free(ptr)?;
+        Ok(new_ptr)
+    }
+
+    fn sbrk_request(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        let end = self.end.checked_add(bytes).and_then(Bump::align)
+            .ok_or(HeapError::HeapExhausted(self.capacity))?;
+        if end > self.capacity {
+            return Err(HeapError::HeapExhausted(self.capacity));
+        }
+        if end < self.next {
+            return Err(HeapError::CannotShrink(self.end - self.next));
+        }
+        self.end = end;
+        Ok(self.end)
+    }
+
+    fn describe_blocks(&self) -> Vec<BlockDescription> {
+        let mut blocks: Vec<BlockDescription> = self.allocations.iter()
+            .map(|a| BlockDescription {
+                id: a.id,
+                offset: a.offset,
+                size: a.size,
+                header: 0,
+                used: if a.freed { 0 } else { a.used },
+                state: if a.freed {
+                    BlockState::Free
+                } else {
+                    BlockState::Allocated
+                },
+                corrupted: false,
+                clobbered: 0,
+            })
+            .collect();
+        if self.next < self.end {
+            // The untouched rest of the heap, which the next malloc bumps
+            // into. Its id can't clash with an allocation's.
+            blocks.push(BlockDescription {
+                id: BlockId::MAX,
+                offset: self.next,
+                size: self.end - self.next,
+                header: 0,
+                used: 0,
+                state: BlockState::Top,
+                corrupted: false,
+                clobbered: 0,
+            });
+        }
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bump() -> Bump {
+        let mut bump = Bump::new();
+        bump.init(0x1000, 256);
+        bump.sbrk_request(128).unwrap();
+        bump
+    }
+
+    #[test]
+    fn mallocs_are_packed_and_aligned() {
+        let mut bump = bump();
+        assert_eq!(bump.malloc(5).unwrap(), 0x1000);
+        assert_eq!(bump.malloc(16).unwrap(), 0x1008);
+        assert_eq!(bump.malloc(1).unwrap(), 0x1018);
+        let blocks = bump.describe_blocks();
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks[3].state == BlockState::Top);
+        assert_eq!((blocks[3].offset, blocks[3].size), (0x20, 96));
+    }
+
+    #[test]
+    fn memory_comes_back_once_everything_is_freed() {
+        let mut bump = bump();
+        let a = bump.malloc(16).unwrap();
+        let b = bump.malloc(16).unwrap();
+        bump.free(a).unwrap();
+        assert_eq!(bump.malloc(16).unwrap(), 0x1020);
+        bump.free(b).unwrap();
+        bump.free(0x1020).unwrap();
+        assert_eq!(bump.malloc(16).unwrap(), 0x1000);
+    }
+
+    #[test]
+    fn bad_frees_are_told_apart() {
+        let mut bump = bump();
+        let a = bump.malloc(16).unwrap();
+        bump.malloc(16).unwrap();
+        assert!(matches!(bump.free(a + 4), Err(HeapError::InteriorPointer(_, p)) if p == a));
+        bump.free(a).unwrap();
+        assert!(matches!(bump.free(a), Err(HeapError::DoubleFree(_))));
+        assert!(matches!(bump.free(0x2000), Err(HeapError::OutOfHeap(_))));
+    }
+
+    #[test]
+    fn only_the_last_allocation_grows_in_place() {
+        let mut bump = bump();
+        let a = bump.malloc(16).unwrap();
+        let b = bump.malloc(16).unwrap();
+        assert_eq!(bump.realloc(b, 40).unwrap(), b);
+        let moved = bump.realloc(a, 40).unwrap();
+        assert_eq!(moved, 0x1038);
+    }
+
+    #[test]
+    fn the_heap_is_bounded() {
+        let mut bump = bump();
+        assert!(matches!(bump.malloc(200), Err(HeapError::OutOfMemory(200))));
+        assert!(matches!(bump.sbrk_request(200), Err(HeapError::HeapExhausted(256))));
+        bump.malloc(100).unwrap();
+        assert!(matches!(bump.sbrk_request(-64), Err(HeapError::CannotShrink(_))));
+    }
+
+    #[test]
+    fn huge_requests_fail_instead_of_overflowing() {
+        let mut bump = bump();
+        let ptr = bump.malloc(16).unwrap();
+        assert!(matches!(bump.malloc(i32::MAX), Err(HeapError::OutOfMemory(_))));
+        assert!(matches!(bump.malloc(i32::MAX - 8), Err(HeapError::OutOfMemory(_))));
+        assert!(matches!(bump.realloc(ptr, i32::MAX), Err(HeapError::OutOfMemory(_))));
+        assert!(matches!(
+            bump.sbrk_request(i32::MAX), Err(HeapError::HeapExhausted(_))));
+    }
+}
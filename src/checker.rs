@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+
+use crate::bins::{large_index, Chunk};
+use crate::heap::{Block, BlockId, Cache, Heap};
+
+// One broken invariant, pinned on the block it was found at if there is one.
+pub struct Violation {
+    pub block: Option<BlockId>,
+    pub message: String,
+}
+
+fn at(block: &Block, message: String) -> Violation {
+    Violation { block: Some(block.id), message: message }
+}
+
+// mm_checkheap: everything that should hold between any two operations.
+// Whatever a simulated bad free or forged header broke shows up here too.
+pub fn check_heap(heap: &Heap) -> Vec<Violation> {
+    let mut violations = vec![];
+    let mut expected = 0;
+    for (i, block) in heap.blocks.iter().enumerate() {
+        let start = heap.base + block.offset;
+        if block.offset != expected {
+            violations.push(at(block, format!(
+                "starts at 0x{:x}, but the block before ends at 0x{:x}",
+                start, heap.base + expected)));
+        }
+        expected = block.end();
+
+        if block.size <= block.header {
+            violations.push(at(block, format!(
+                "is {} B, too small for its {} B header", block.size, block.header)));
+        }
+        if block.size % heap.alignment != 0 || block.offset % heap.alignment != 0 {
+            violations.push(at(block, format!(
+                "isn't aligned to {} B", heap.alignment)));
+        }
+        if block.space_used > block.payload() {
+            violations.push(at(block, format!(
+                "uses {} B of a {} B payload", block.space_used, block.payload())));
+        }
+        if !block.allocated && block.space_used != 0 {
+            violations.push(at(block, format!(
+                "is free but still uses {} B", block.space_used)));
+        }
+        if block.header_size() != block.size ||
+                block.header_allocated() != block.allocated {
+            violations.push(at(block, format!(
+                "has a header saying {} B {}, but is {} B {}",
+                block.header_size(),
+                if block.header_allocated() { "in use" } else { "free" },
+                block.size,
+                if block.allocated { "in use" } else { "free" })));
+        }
+
+        // ptmalloc and managed heaps coalesce on every free, so free
+        // neighbors mean a merge was missed.
+        let coalescing = heap.bins.is_some() || heap.managed;
+        if let (true, Some(next)) = (coalescing, heap.blocks.get(i + 1)) {
+            if !block.allocated && !next.allocated {
+                violations.push(at(next, format!(
+                    "is free right after the free block at 0x{:x}", start)));
+            }
+        }
+    }
+    if expected != heap.end_of_heap_bytes {
+        violations.push(Violation {
+            block: heap.blocks.last().map(|b| b.id),
+            message: format!(
+                "the blocks end at 0x{:x}, but the heap ends at 0x{:x}",
+                heap.base + expected, heap.base + heap.end_of_heap_bytes),
+        });
+    }
+
+    violations.extend(check_free_lists(heap));
+    violations
+}
+
+// Every free chunk but the top is on exactly one list, in the right bin,
+// and every listed chunk is a block of the size it is listed with.
+fn check_free_lists(heap: &Heap) -> Vec<Violation> {
+    let mut violations = vec![];
+    let bins = match &heap.bins {
+        Some(bins) => bins,
+        None => {
+            for block in heap.blocks.iter() {
+                if block.cached == Some(Cache::Fastbin) {
+                    violations.push(at(block, String::from(
+                        "is in a fastbin, but the heap has no bins")));
+                }
+            }
+            return violations;
+        }
+    };
+    let top = heap.top();
+
+    let mut listed: BTreeMap<i32, usize> = BTreeMap::new();
+    let mut check = |name: String, chunk: &Chunk, fits: bool, fast: bool| {
+        *listed.entry(chunk.ptr).or_insert(0) += 1;
+        let block = match heap.blocks.iter().position(|b| b.addr() == chunk.ptr) {
+            Some(i) => &heap.blocks[i],
+            None => {
+                violations.push(Violation {
+                    block: None,
+                    message: format!("{} holds 0x{:x}, which isn't a block",
+                        name, chunk.ptr),
+                });
+                return;
+            }
+        };
+        if block.size != chunk.size {
+            violations.push(at(block, format!(
+                "is {} B, but {} lists it as {} B", block.size, name, chunk.size)));
+        }
+        if !fits {
+            violations.push(at(block, format!(
+                "is in {}, which doesn't hold {} B chunks", name, chunk.size)));
+        }
+        let free = if fast {
+            block.cached == Some(Cache::Fastbin)
+        } else {
+            !block.allocated && top != heap.index_of(block.id)
+        };
+        if !free {
+            violations.push(at(block, format!(
+                "is in {}, but isn't free there", name)));
+        }
+    };
+
+    for (size, list) in bins.fast.iter() {
+        for chunk in list.iter() {
+            check(format!("fastbin {}", size), chunk, chunk.size == *size, true);
+        }
+    }
+    for chunk in bins.unsorted.iter() {
+        check(String::from("the unsorted bin"), chunk, true, false);
+    }
+    for (size, list) in bins.small.iter() {
+        for chunk in list.iter() {
+            check(format!("smallbin {}", size), chunk, chunk.size == *size, false);
+        }
+    }
+    for (index, list) in bins.large.iter() {
+        for chunk in list.iter() {
+            let fits = large_index(chunk.size) == *index;
+            check(format!("largebin #{}", index), chunk, fits, false);
+        }
+    }
+
+    for (i, block) in heap.blocks.iter().enumerate() {
+        let count = listed.get(&block.addr()).cloned().unwrap_or(0);
+        let should = block.cached == Some(Cache::Fastbin) ||
+            (!block.allocated && top != Some(i));
+        if count > 1 {
+            violations.push(at(block, format!("is on the free lists {} times", count)));
+        } else if should && count == 0 {
+            violations.push(at(block, String::from("is free but on no list")));
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bins::Bins;
+    use crate::heap::ForgedHeader;
+
+    // Three 224 B chunks from 0x1000 under ptmalloc, the middle one in the
+    // unsorted bin, and the top chunk after them.
+    fn heap() -> Heap {
+        let mut heap = Heap::with_geometry(0x1000, 16, 16, 1 << 12);
+        heap.bins = Some(Bins::new());
+        heap.sbrk(1024).unwrap();
+        let ptrs: Vec<i32> = (0..3).map(|_| heap.malloc(200).unwrap()).collect();
+        heap.free(ptrs[1]).unwrap();
+        heap
+    }
+
+    fn messages(heap: &Heap) -> Vec<String> {
+        check_heap(heap).into_iter().map(|v| v.message).collect()
+    }
+
+    #[test]
+    fn consistent_heaps_pass() {
+        assert!(check_heap(&heap()).is_empty());
+    }
+
+    #[test]
+    fn overlapping_blocks_are_caught() {
+        let mut heap = heap();
+        heap.blocks[1].offset -= 16;
+        let violations = check_heap(&heap);
+        assert!(violations.iter().any(|v| v.block == Some(heap.blocks[1].id) &&
+            v.message == "starts at 0x10d0, but the block before ends at 0x10e0"));
+    }
+
+    #[test]
+    fn bad_free_list_links_are_caught() {
+        let mut heap = heap();
+        heap.bins.as_mut().unwrap().push_unsorted(Chunk { ptr: 0x1234, size: 48 });
+        let violations = check_heap(&heap);
+        assert!(violations.iter().any(|v| v.block.is_none() &&
+            v.message == "the unsorted bin holds 0x1234, which isn't a block"));
+    }
+
+    #[test]
+    fn forged_sizes_are_caught() {
+        let mut heap = heap();
+        heap.blocks[0].forged = Some(ForgedHeader { size: 96, allocated: true });
+        assert!(messages(&heap).contains(&String::from(
+            "has a header saying 96 B in use, but is 224 B in use")));
+    }
+
+    #[test]
+    fn missed_merges_are_caught() {
+        let mut heap = heap();
+        heap.blocks[2].allocated = false;
+        heap.blocks[2].space_used = 0;
+        assert!(messages(&heap).contains(&String::from(
+            "is free right after the free block at 0x10e0")));
+    }
+}
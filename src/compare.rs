@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+
+use malloc_vis::heap::HeapError;
+use malloc_vis::metrics::Metrics;
+use malloc_vis::trace::{Op, Trace, TraceRunner};
+use crate::arena::Arena;
+use crate::objects::STAT_TEXT;
+use crate::text::Text;
+
+use quicksilver::{
+    Result,
+    geom::Vector,
+    graphics::Color,
+    lifecycle::Window
+};
+
+// How much a policy that ran out of heap sbrks beyond what it needs, so a
+// comparison doesn't stop at every op to have the heaps grown by hand.
+static GROW: i32 = 64;
+
+// What one arena made of an op: the pointer it produced, if any.
+pub type OpResult = std::result::Result<Option<i32>, HeapError>;
+
+// One trace played in lockstep on every arena, each running its own policy.
+pub struct Comparison {
+    // One per arena, since each policy hands out different pointers.
+    pub runners: Vec<TraceRunner>,
+    pub metrics: Vec<Metrics>,
+    // The op after which the policies first placed a block differently.
+    pub diverged_at: Option<usize>,
+}
+
+impl Comparison {
+    pub fn new(trace: Trace, arenas: usize) -> Self {
+        let runners = (0..arenas).map(|_| {
+            let mut runner = TraceRunner::new(trace.clone());
+            runner.grow = GROW;
+            runner
+        }).collect();
+        Comparison {
+            runners: runners,
+            metrics: vec![Metrics::new(); arenas],
+            diverged_at: None,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.runners.first().map_or(0, |runner| runner.trace.ops.len())
+    }
+
+    pub fn next(&self) -> usize {
+        self.runners.first().map_or(0, |runner| runner.next)
+    }
+
+    // Runs the next op in every arena, returning it with what each arena
+    // made of it, or None once the trace is done.
+    pub fn step(
+            &mut self,
+            arenas: &mut [Arena])
+            -> Option<(Op, Vec<OpResult>)> {
+        let mut op = None;
+        let mut results = vec![];
+        let stepped = self.runners.iter_mut().zip(self.metrics.iter_mut());
+        for ((runner, metrics), arena) in stepped.zip(arenas.iter_mut()) {
+            let (next, result) = runner.step(arena.allocator_mut())?;
+            metrics.record(arena.allocator(), result.is_ok());
+            op = Some(next);
+            results.push(result);
+        }
+        self.mark_divergence(arenas);
+        op.map(|op| (op, results))
+    }
+
+    // Flags the blocks of every trace id that isn't at the same offset in
+    // all the arenas, including ids that some policies failed to place.
+    fn mark_divergence(&mut self, arenas: &mut [Arena]) {
+        for arena in arenas.iter_mut() {
+            arena.diverged.clear();
+        }
+        let ids: BTreeSet<usize> = self.runners.iter()
+            .flat_map(|runner| runner.ptrs.keys().cloned())
+            .collect();
+        for id in ids.iter() {
+            let offsets: Vec<Option<i32>> = self.runners.iter().zip(arenas.iter())
+                .map(|(runner, arena)| runner.ptrs.get(id).map(|ptr| ptr - arena.heap.base))
+                .collect();
+            if offsets.iter().all(|offset| *offset == offsets[0]) {
+                continue;
+            }
+            for (offset, arena) in offsets.iter().zip(arenas.iter_mut()) {
+                if let Some(offset) = offset {
+                    arena.diverged.push(*offset);
+                }
+            }
+        }
+
+        let diverged = arenas.iter().any(|arena| !arena.diverged.is_empty());
+        if diverged && self.diverged_at.is_none() {
+            self.diverged_at = Some(self.next());
+        }
+    }
+
+    // Each policy's numbers so far, under its arena's label so they line up
+    // for comparing.
+    pub fn draw_metrics(
+            &self,
+            window: &mut Window,
+            text: &mut Text,
+            arenas: &[Arena]) -> Result<()> {
+        for (metrics, arena) in self.metrics.iter().zip(arenas.iter()) {
+            let line = format!(
+                "util {:.1}%  peak {} B  frag {:.1}%  {} failed",
+                metrics.utilization() * 100.0,
+                metrics.peak_heap,
+                metrics.fragmentation() * 100.0,
+                metrics.failures);
+            // Below the heap check's count when there is one.
+            let rows = if arena.violations.is_empty() { 1.0 } else { 2.0 };
+            let x = window.screen_size().x - Text::width(&line, STAT_TEXT) - 5.0;
+            text.draw(
+                window, &line, Vector::new(x, arena.view.top + rows * STAT_TEXT),
+                STAT_TEXT, Color::BLACK)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use malloc_vis::config::Config;
+
+    fn arenas(policies: &str) -> Vec<Arena> {
+        let config = Config {
+            arenas: 2,
+            compare: String::from(policies),
+            ..Config::default()
+        };
+        (0..2).map(|i| Arena::new(i, &config)).collect()
+    }
+
+    fn run(comparison: &mut Comparison, arenas: &mut [Arena]) {
+        while let Some((_, results)) = comparison.step(arenas) {
+            assert!(results.iter().all(|result| result.is_ok()));
+        }
+    }
+
+    #[test]
+    fn the_same_policy_never_diverges() {
+        let trace = Trace::parse("a 0 100\na 1 16\nf 0\na 2 40\n").unwrap();
+        let mut arenas = arenas("first-fit,first-fit");
+        let mut comparison = Comparison::new(trace, arenas.len());
+        run(&mut comparison, &mut arenas);
+        assert_eq!(comparison.next(), comparison.total());
+        assert_eq!(comparison.diverged_at, None);
+        assert!(arenas.iter().all(|arena| arena.diverged.is_empty()));
+        assert_eq!(comparison.metrics[0].ops, 4);
+        assert_eq!(comparison.metrics[0].peak_payload, comparison.metrics[1].peak_payload);
+    }
+
+    #[test]
+    fn divergence_is_found_at_the_first_differing_op() {
+        // First fit reuses the big hole and best fit the one that fits.
+        let trace = Trace::parse(
+            "a 0 100\na 1 16\na 2 40\na 3 16\nf 0\nf 2\na 4 40\n").unwrap();
+        let mut arenas = arenas("first-fit,best-fit");
+        let mut comparison = Comparison::new(trace, arenas.len());
+        run(&mut comparison, &mut arenas);
+        assert_eq!(comparison.diverged_at, Some(7));
+        let ptrs: Vec<i32> = comparison.runners.iter().map(|runner| runner.ptrs[&4]).collect();
+        assert_ne!(ptrs[0] - arenas[0].heap.base, ptrs[1] - arenas[1].heap.base);
+        for (arena, ptr) in arenas.iter().zip(ptrs.iter()) {
+            assert_eq!(arena.diverged, vec![ptr - arena.heap.base]);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use crate::allocator::{ALLOCATORS, POLICIES};
+use crate::constants::*;
+use crate::workload::Workload;
+
+// Heap geometry and layout, chosen at startup. On the web these come from the
+// page's query string, e.g. `?capacity=16384&header=8&align=8`, and native
+// builds read the same keys one per line from CONFIG_FILE.
+#[derive(Clone)]
+pub struct Config {
+    pub heap_capacity: i32,
+    pub header_size: i32,
+    pub alignment: i32,
+    // Number of independent heaps, stacked one above the other.
+    pub arenas: i32,
+    // Blocks each tcache bin holds, or 0 to free straight to the heap.
+    pub tcache_count: i32,
+    // Largest block size that goes into a tcache.
+    pub tcache_max: i32,
+    // Emulate glibc's bins instead of a plain first fit heap.
+    pub ptmalloc: bool,
+    // Show a heap of size-class pages as a grid instead of the arenas.
+    pub pages: bool,
+    pub page_size: i32,
+    // Which allocator runs each arena, one of ALLOCATORS or POLICIES.
+    pub allocator: String,
+    // Policies to run the same trace on side by side, separated by commas.
+    // Each gets its own arena, in place of `arenas` and `allocator`.
+    pub compare: String,
+    // A generated trace to step through when none is loaded, see Workload.
+    pub workload: String,
+    pub px_per_byte: f32,
+    pub menu_px: i32,
+    // Draw headers as gaps between blocks rather than as part of them.
+    pub render_gaps: bool,
+    // Bytes per row when the heap is wrapped into rows, or 0 for one strip.
+    pub row_bytes: i32,
+    pub visible_rows: i32,
+    // How fast operations are animated, or 0 to apply them instantly.
+    pub anim_speed: f32,
+    pub window_width: i32,
+    pub window_height: i32,
+}
+
+pub static CONFIG_FILE: &str = "malloc-vis.conf";
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            heap_capacity: TOTAL_MEMORY,
+            header_size: MEM_GAP,
+            alignment: 1,
+            arenas: 1,
+            tcache_count: TCACHE_COUNT,
+            tcache_max: TCACHE_MAX,
+            ptmalloc: false,
+            pages: false,
+            page_size: PAGE_SIZE,
+            allocator: String::from("builtin"),
+            compare: String::new(),
+            workload: String::new(),
+            px_per_byte: PX_PER_BYTE as f32,
+            menu_px: SBRK_MENU_PX,
+            render_gaps: true,
+            row_bytes: 0,
+            visible_rows: 4,
+            anim_speed: 1.0,
+            window_width: 20 * SBRK_MENU_PX,
+            window_height: 600,
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.trim().parse::<T>()
+        .map_err(|_| format!("Invalid value {:?} for {}", value, key))
+}
+
+// Undoes the percent-encoding browsers apply to query strings, so
+// `fifo%2Cexp%3A64` reads as `fifo,exp:64`.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = text.get(i + 1..i + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+impl Config {
+    // Applies `key=value` pairs separated by `separator`. Everything that
+    // parses is applied even if some pairs are rejected.
+    pub fn parse(&mut self, text: &str, separator: char) -> Result<(), String> {
+        self.parse_with(text, separator, str::to_owned)
+    }
+
+    // Applies the page's query string, whose values are percent-encoded.
+    pub fn parse_query(&mut self, query: &str) -> Result<(), String> {
+        self.parse_with(query, '&', percent_decode)
+    }
+
+    fn parse_with(
+            &mut self,
+            text: &str,
+            separator: char,
+            decode: fn(&str) -> String) -> Result<(), String> {
+        let mut errors = vec![];
+        for pair in text.split(separator) {
+            let pair = pair.trim();
+            if pair.is_empty() || pair.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("");
+            if let Err(err) = self.set(key, &decode(value)) {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("\n")) }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "capacity" => self.heap_capacity = parse_value(key, value)?,
+            "header" => self.header_size = parse_value(key, value)?,
+            "align" => self.alignment = parse_value(key, value)?,
+            "arenas" => self.arenas = parse_value(key, value)?,
+            "tcache" => self.tcache_count = parse_value(key, value)?,
+            "tcache_max" => self.tcache_max = parse_value(key, value)?,
+            "ptmalloc" => self.ptmalloc = parse_value(key, value)?,
+            "pages" => self.pages = parse_value(key, value)?,
+            "page_size" => self.page_size = parse_value(key, value)?,
+            "allocator" => self.allocator = String::from(value.trim()),
+            "compare" => self.compare = String::from(value.trim()),
+            "workload" => {
+                self.workload = String::from(value.trim());
+                if !self.workload.is_empty() {
+                    if let Err(err) = Workload::parse(&self.workload) {
+                        self.workload = String::new();
+                        return Err(err);
+                    }
+                }
+            }
+            "zoom" => self.px_per_byte = parse_value(key, value)?,
+            "menu" => self.menu_px = parse_value(key, value)?,
+            "gaps" => self.render_gaps = parse_value(key, value)?,
+            "row" => self.row_bytes = parse_value(key, value)?,
+            "rows" => self.visible_rows = parse_value(key, value)?,
+            "speed" => self.anim_speed = parse_value(key, value)?,
+            "width" => self.window_width = parse_value(key, value)?,
+            "height" => self.window_height = parse_value(key, value)?,
+            _ => return Err(format!("Unknown setting {:?}", key)),
+        }
+        self.validate(key)
+    }
+
+    // Puts anything out of range back to its default.
+    fn validate(&mut self, key: &str) -> Result<(), String> {
+        let default = Config::default();
+        let mut ok = true;
+        if self.heap_capacity <= 0 || self.heap_capacity > MAX_CAPACITY {
+            self.heap_capacity = default.heap_capacity;
+            ok = false;
+        }
+        if self.header_size <= 0 {
+            self.header_size = default.header_size;
+            ok = false;
+        }
+        if self.alignment <= 0 {
+            self.alignment = default.alignment;
+            ok = false;
+        }
+        if self.arenas <= 0 || self.arenas > MAX_ARENAS {
+            self.arenas = default.arenas;
+            ok = false;
+        }
+        if self.tcache_count < 0 {
+            self.tcache_count = default.tcache_count;
+            ok = false;
+        }
+        if self.tcache_max < 0 {
+            self.tcache_max = default.tcache_max;
+            ok = false;
+        }
+        if self.page_size < 32 {
+            self.page_size = default.page_size;
+            ok = false;
+        }
+        let name = &&self.allocator[..];
+        if !ALLOCATORS.contains(name) && !POLICIES.contains(name) {
+            self.allocator = default.allocator;
+            ok = false;
+        }
+        let compared = self.compared().len();
+        let known = self.compared().iter()
+            .all(|name| ALLOCATORS.contains(name) || POLICIES.contains(name));
+        if !known || compared == 1 || compared > MAX_COMPARED {
+            self.compare = default.compare;
+            ok = false;
+        } else if compared > 0 {
+            self.arenas = compared as i32;
+        }
+        if self.px_per_byte <= 0.0 {
+            self.px_per_byte = default.px_per_byte;
+            ok = false;
+        }
+        if self.menu_px < 20 {
+            self.menu_px = default.menu_px;
+            ok = false;
+        }
+        if self.row_bytes < 0 {
+            self.row_bytes = default.row_bytes;
+            ok = false;
+        }
+        if self.visible_rows <= 0 {
+            self.visible_rows = default.visible_rows;
+            ok = false;
+        }
+        if self.anim_speed < 0.0 {
+            self.anim_speed = default.anim_speed;
+            ok = false;
+        }
+        if self.window_width <= 0 || self.window_height <= 0 {
+            self.window_width = default.window_width;
+            self.window_height = default.window_height;
+            ok = false;
+        }
+
+        if ok { Ok(()) } else { Err(format!("Value for {} is out of range", key)) }
+    }
+
+    // The policies named by `compare`, if any.
+    pub fn compared(&self) -> Vec<&str> {
+        self.compare.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    pub fn to_query(&self) -> String {
+        format!(
+            concat!("capacity={}&header={}&align={}&arenas={}",
+                "&tcache={}&tcache_max={}&ptmalloc={}&pages={}&page_size={}",
+                "&allocator={}&compare={}&workload={}&zoom={}&menu={}&gaps={}",
+                "&row={}&rows={}&speed={}&width={}&height={}"),
+            self.heap_capacity,
+            self.header_size,
+            self.alignment,
+            self.arenas,
+            self.tcache_count,
+            self.tcache_max,
+            self.ptmalloc,
+            self.pages,
+            self.page_size,
+            self.allocator,
+            self.compare,
+            self.workload,
+            self.px_per_byte,
+            self.menu_px,
+            self.render_gaps,
+            self.row_bytes,
+            self.visible_rows,
+            self.anim_speed,
+            self.window_width,
+            self.window_height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_pair_is_applied() {
+        let mut config = Config::default();
+        config.parse("capacity=4096&header=8&align=16&ptmalloc=true", '&').unwrap();
+        assert_eq!(config.heap_capacity, 4096);
+        assert_eq!(config.header_size, 8);
+        assert_eq!(config.alignment, 16);
+        assert!(config.ptmalloc);
+    }
+
+    #[test]
+    fn files_skip_blank_lines_and_comments() {
+        let mut config = Config::default();
+        config.parse("# a comment\n\nrows = 6\n", '\n').unwrap();
+        assert_eq!(config.visible_rows, 6);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_and_the_rest_applied() {
+        let mut config = Config::default();
+        let err = config.parse("colour=red&header=8", '&').unwrap_err();
+        assert_eq!(err, "Unknown setting \"colour\"");
+        assert_eq!(config.header_size, 8);
+    }
+
+    #[test]
+    fn out_of_range_values_go_back_to_their_defaults() {
+        let mut config = Config::default();
+        let err = config.parse("capacity=-5&header=8&arenas=99", '&').unwrap_err();
+        assert_eq!(err, concat!("Value for capacity is out of range\n",
+            "Value for arenas is out of range"));
+        assert_eq!(config.heap_capacity, TOTAL_MEMORY);
+        assert_eq!(config.arenas, 1);
+        assert_eq!(config.header_size, 8);
+
+        assert!(config.parse("capacity=2000000000", '&').is_err());
+        assert_eq!(config.heap_capacity, TOTAL_MEMORY);
+    }
+
+    #[test]
+    fn compare_needs_two_to_four_known_policies() {
+        let mut config = Config::default();
+        assert!(config.parse("compare=first-fit", '&').is_err());
+        assert_eq!(config.compare, "");
+        assert!(config.parse(
+            "compare=first-fit,next-fit,best-fit,worst-fit,ptmalloc", '&').is_err());
+        assert_eq!(config.compare, "");
+        assert_eq!(config.arenas, 1);
+
+        config.parse("compare=first-fit, best-fit", '&').unwrap();
+        assert_eq!(config.compared(), vec!["first-fit", "best-fit"]);
+        assert_eq!(config.arenas, 2);
+    }
+
+    #[test]
+    fn query_values_are_percent_decoded() {
+        let mut config = Config::default();
+        config.parse_query("workload=fifo%2Cexp%3A64&compare=bump,pages").unwrap();
+        assert_eq!(config.workload, "fifo,exp:64");
+        assert_eq!(config.compare, "bump,pages");
+        assert_eq!(percent_decode("100%25+done%"), "100% done%");
+    }
+}
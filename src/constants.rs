@@ -1,4 +1,13 @@
-pub static TOTAL_MEMORY: i32 = 4*1024;
+// Defaults for the runtime settings in config.rs.
+pub static TOTAL_MEMORY: i32 = 64*1024;
 pub static PX_PER_BYTE: i32 = 1;
 pub static SBRK_MENU_PX: i32 = 100;
 pub static MEM_GAP: i32 = 5;
+pub static HEAP_BASE: i32 = 4096;
+pub static MAX_ARENAS: i32 = 8;
+// Small enough that MAX_ARENAS heaps side by side still have i32 addresses.
+pub static MAX_CAPACITY: i32 = 1 << 27;
+pub static MAX_COMPARED: usize = 4;
+pub static TCACHE_COUNT: i32 = 7;
+pub static TCACHE_MAX: i32 = 1032;
+pub static PAGE_SIZE: i32 = 4096;
@@ -0,0 +1,1087 @@
+use crate::bins::{Bins, Chunk, FASTBIN_MAX, LARGEBIN_MIN};
+
+use std::fmt;
+
+pub type BlockId = usize;
+
+// What the allocator reads back from a header after an overflow has written
+// over it. As in ptmalloc, the low bit of the written size is the in-use bit.
+#[derive(Clone, Copy)]
+pub struct ForgedHeader {
+    pub size: i32,
+    pub allocated: bool,
+}
+
+impl ForgedHeader {
+    pub fn from_raw(raw: i32) -> Self {
+        ForgedHeader {
+            size: raw & !1,
+            allocated: raw & 1 == 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ByteKind {
+    Header,
+    Payload,
+    Padding,
+    FreeListPtr,
+    Clobbered,
+}
+
+// The operation that produced a block or handed it out.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Origin {
+    Sbrk,
+    Split,
+    BadFree,
+    Malloc,
+    Realloc,
+    Tcache,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Sbrk => write!(f, "sbrk"),
+            Origin::Split => write!(f, "split"),
+            Origin::BadFree => write!(f, "simulated bad free"),
+            Origin::Malloc => write!(f, "malloc"),
+            Origin::Realloc => write!(f, "realloc"),
+            Origin::Tcache => write!(f, "malloc via tcache"),
+        }
+    }
+}
+
+// A freed block that is still marked in use, because something other than
+// the heap's own free list is holding on to it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Cache {
+    Tcache(usize),
+    Fastbin,
+}
+
+// Which free block malloc picks when several are big enough.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fit {
+    First,
+    // First fit, starting from where the last search left off.
+    Next,
+    Best,
+    Worst,
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fit::First => write!(f, "first fit"),
+            Fit::Next => write!(f, "next fit"),
+            Fit::Best => write!(f, "best fit"),
+            Fit::Worst => write!(f, "worst fit"),
+        }
+    }
+}
+
+// Lays `value` out little-endian over `len` bytes, truncating or zero
+// padding as needed.
+fn le_bytes(value: i32, len: i32) -> Vec<u8> {
+    (0..len)
+        .map(|i| if i < 4 { (value >> (8 * i)) as u8 } else { 0 })
+        .collect()
+}
+
+pub struct Block {
+    pub id: BlockId,
+    // Address of the start of the heap the block belongs to.
+    pub base: i32,
+    pub offset: i32,
+    pub size: i32,
+    pub header: i32,
+    pub allocated: bool,
+    pub space_used: i32,
+    // Set when a simulated bad free has left the allocator's view of this
+    // block inconsistent with what the program is actually doing with it.
+    pub corrupted: bool,
+    // Number of bytes from the start of the block, header first, that an
+    // overflow from the previous block has written over.
+    pub clobbered: i32,
+    pub forged: Option<ForgedHeader>,
+    pub origin: Origin,
+    // Sequence number of the call that handed this block out, and which
+    // call it was, while the block is allocated.
+    pub allocation: Option<(usize, Origin)>,
+    // Where the block waits if it was freed into a tcache or fastbin. Like
+    // glibc, such blocks stay marked in use so they are never coalesced or
+    // handed out by the heap.
+    pub cached: Option<Cache>,
+}
+
+impl Block {
+    // Bytes available to the user, i.e. everything after the header.
+    pub fn payload(&self) -> i32 {
+        self.size - self.header
+    }
+
+    // The pointer malloc hands out for this block.
+    pub fn addr(&self) -> i32 {
+        self.base + self.offset + self.header
+    }
+
+    pub fn end(&self) -> i32 {
+        self.offset + self.size
+    }
+
+    // The size the allocator believes this block has, going by its header.
+    pub fn header_size(&self) -> i32 {
+        self.forged.map_or(self.size, |h| h.size)
+    }
+
+    pub fn header_allocated(&self) -> bool {
+        self.forged.map_or(self.allocated, |h| h.allocated)
+    }
+
+    // Payload bytes the program asked for but isn't using.
+    pub fn padding(&self) -> i32 {
+        if self.allocated { self.payload() - self.space_used } else { 0 }
+    }
+
+    // One line per fact about the block, for tooltips and the like.
+    pub fn describe(&self) -> Vec<String> {
+        let start = self.base + self.offset;
+        let mut lines = vec![
+            format!("block #{} at 0x{:x} ({})", self.id, start, start),
+            format!("size {} B: header {} B, no footer", self.size, self.header),
+        ];
+        if let Some(Cache::Tcache(thread)) = self.cached {
+            lines.push(format!("{} B free, in thread {}'s tcache",
+                self.payload(), thread));
+        } else if self.cached == Some(Cache::Fastbin) {
+            lines.push(format!("{} B free, in a fastbin", self.payload()));
+        } else if self.allocated {
+            lines.push(format!("payload {} B used, {} B padding",
+                self.space_used, self.padding()));
+        } else {
+            lines.push(format!("{} B free", self.payload()));
+        }
+        lines.push(format!("created by {}", self.origin));
+        if let Some((n, by)) = self.allocation {
+            lines.push(format!("allocation #{} from {}, ptr 0x{:x}",
+                n, by, self.addr()));
+        }
+        if self.corrupted {
+            lines.push(String::from("corrupted"));
+        }
+        lines
+    }
+}
+
+#[derive(Debug)]
+pub enum HeapError {
+    NegativeSize,
+    BlockTooSmall(i32),
+    BelowMinimumSize(i32),
+    SplitTooLarge(i32),
+    SplitWouldClobber(i32),
+    NoFreeNeighbor,
+    NoSuchBlock,
+    OutOfMemory(i32),
+    InvalidPointer(i32),
+    DoubleFree(i32),
+    InteriorPointer(i32, i32),
+    OutOfHeap(i32),
+    NotAllocated,
+    HeapExhausted(i32),
+    CannotShrink(i32),
+    Unsupported(&'static str),
+    // Anything a loaded allocator reports, in its own words.
+    External(String),
+}
+
+impl HeapError {
+    // The message glibc prints before aborting, for errors that come from
+    // handing free a bad pointer.
+    pub fn glibc_message(&self) -> Option<&'static str> {
+        match self {
+            HeapError::DoubleFree(_) =>
+                Some("free(): double free detected in tcache 2"),
+            HeapError::InteriorPointer(_, _) =>
+                Some("free(): invalid pointer"),
+            HeapError::OutOfHeap(_) =>
+                Some("munmap_chunk(): invalid pointer"),
+            HeapError::InvalidPointer(_) =>
+                Some("double free or corruption (!prev)"),
+            _ => None,
+        }
+    }
+
+    pub fn explanation(&self) -> Vec<&'static str> {
+        match self {
+            HeapError::DoubleFree(_) => vec![
+                "glibc spots the chunk already sitting in a bin and aborts.",
+                "Without the check the chunk is linked into the free list",
+                "twice, so two later mallocs hand out the same memory.",
+            ],
+            HeapError::InteriorPointer(_, _) => vec![
+                "free reads the header just before the pointer, which here",
+                "is user data in the middle of a block. glibc's size and",
+                "alignment checks abort; a naive allocator trusts the data",
+                "and frees a bogus block overlapping live memory.",
+            ],
+            HeapError::OutOfHeap(_) => vec![
+                "The address is not inside the heap at all. The bogus",
+                "header usually looks like an mmapped chunk, so glibc",
+                "tries to munmap it and aborts; otherwise it segfaults.",
+            ],
+            HeapError::InvalidPointer(_) => vec![
+                "This block was never returned by malloc. Its header says",
+                "it is already free, so the next chunk's PREV_INUSE bit is",
+                "clear and glibc aborts.",
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeapError::NegativeSize =>
+                write!(f, "Must allocate a positive amount."),
+            HeapError::BlockTooSmall(payload) =>
+                write!(f, concat!("The block is too small to store that",
+                    " amount! It only has room for {} bytes."), payload),
+            HeapError::BelowMinimumSize(min) =>
+                write!(f, concat!("Due to rendering constraints,",
+                    " the minimum block size is {}"), min),
+            HeapError::SplitTooLarge(size) =>
+                write!(f, concat!("Can't split this block with given new size.",
+                    " This block only has {} bytes."), size),
+            HeapError::SplitWouldClobber(needed) =>
+                write!(f, concat!("Can't split this block with given new size.",
+                    " This block must have at least {} bytes."), needed),
+            HeapError::NoFreeNeighbor =>
+                write!(f, "Can't coalesce without a free neighbor."),
+            HeapError::NoSuchBlock =>
+                write!(f, "That block no longer exists."),
+            HeapError::OutOfMemory(bytes) =>
+                write!(f, concat!("No free block can hold {} bytes.",
+                    " Try sbrk-ing more memory."), bytes),
+            HeapError::InvalidPointer(ptr) =>
+                write!(f, concat!("free(): invalid pointer {}.",
+                    " It was never returned by malloc."), ptr),
+            HeapError::DoubleFree(ptr) =>
+                write!(f, "free(): double free detected for pointer {}.", ptr),
+            HeapError::InteriorPointer(ptr, start) =>
+                write!(f, concat!("free(): pointer {} points into the middle",
+                    " of the block at {}."), ptr, start),
+            HeapError::OutOfHeap(ptr) =>
+                write!(f, "free(): pointer {} is outside of the heap.", ptr),
+            HeapError::NotAllocated =>
+                write!(f, "Only allocated blocks can be written to."),
+            HeapError::HeapExhausted(capacity) =>
+                write!(f, "The heap can't grow past {} bytes.", capacity),
+            HeapError::CannotShrink(free) =>
+                write!(f, concat!("Only free bytes at the top of the heap can",
+                    " be given back, and there are {} of them."), free),
+            HeapError::Unsupported(what) =>
+                write!(f, "This allocator doesn't support {}.", what),
+            HeapError::External(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+pub struct Heap {
+    pub blocks: Vec<Block>,
+    // Address of the first byte of the heap.
+    pub base: i32,
+    pub end_of_heap_bytes: i32,
+    pub header: i32,
+    pub alignment: i32,
+    pub capacity: i32,
+    next_id: BlockId,
+    next_allocation: usize,
+    // Pointers that were handed out and then freed, so a second free can be
+    // told apart from a pointer that was never valid.
+    freed: Vec<i32>,
+    // Contents of the heap as written by the program. Headers and free list
+    // pointers are derived from the blocks instead, see `dump`.
+    memory: Vec<u8>,
+    // Set to emulate glibc's ptmalloc: malloc and free go through these bins
+    // and the free block at the end of the heap is the top chunk.
+    pub bins: Option<Bins>,
+    pub fit: Fit,
+    // Set when the heap places blocks by itself, as when running a trace:
+    // malloc splits off what it doesn't need, free merges with free
+    // neighbors and sbrk grows a free last block. Otherwise that is left to
+    // the buttons.
+    pub managed: bool,
+    // Where next fit resumes its search.
+    rover: i32,
+}
+
+impl Heap {
+    pub fn with_geometry(
+            base: i32, header: i32, alignment: i32, capacity: i32) -> Self {
+        Heap {
+            blocks: vec![],
+            base: base,
+            end_of_heap_bytes: 0,
+            header: header,
+            alignment: alignment,
+            capacity: capacity,
+            next_id: 0,
+            next_allocation: 0,
+            freed: vec![],
+            memory: vec![],
+            bins: None,
+            fit: Fit::First,
+            managed: false,
+            rover: 0,
+        }
+    }
+
+    fn new_block(&mut self, offset: i32, size: i32, origin: Origin) -> Block {
+        let id = self.next_id;
+        self.next_id += 1;
+        Block {
+            id: id,
+            base: self.base,
+            offset: offset,
+            size: size,
+            header: self.header,
+            allocated: false,
+            space_used: 0,
+            corrupted: false,
+            clobbered: 0,
+            forged: None,
+            origin: origin,
+            allocation: None,
+            cached: None,
+        }
+    }
+
+    // A naive allocator takes whatever is in a block's header at face value.
+    // Once it does, the block really is the size the header claims, even if
+    // that means it now overlaps its neighbors.
+    fn trust_header(&mut self, idx: usize) {
+        let limit = self.end_of_heap_bytes - self.blocks[idx].offset;
+        let block = &mut self.blocks[idx];
+        if let Some(header) = block.forged.take() {
+            block.size = header.size.min(limit).max(block.header + 1);
+            block.space_used = block.space_used.min(block.payload());
+            block.corrupted = true;
+        }
+    }
+
+    // ptmalloc's top chunk, the free block at the end of the heap that new
+    // chunks are carved from when no bin has one.
+    pub fn top(&self) -> Option<usize> {
+        match self.blocks.last() {
+            Some(last) if !last.allocated => Some(self.blocks.len() - 1),
+            _ => None,
+        }
+    }
+
+    // Fixes up the bins after anything that moves blocks around by hand.
+    fn sync_bins(&mut self) {
+        if let Some(bins) = &mut self.bins {
+            bins.sync(&self.blocks);
+        }
+    }
+
+    // Whether `ptr` falls anywhere this heap could grow to.
+    pub fn owns(&self, ptr: i32) -> bool {
+        self.base <= ptr && ptr < self.base + self.capacity
+    }
+
+    pub fn index_of(&self, id: BlockId) -> Option<usize> {
+        self.blocks.iter().position(|b| b.id == id)
+    }
+
+    pub fn block(&self, id: BlockId) -> Option<&Block> {
+        self.index_of(id).map(|i| &self.blocks[i])
+    }
+
+    fn find_ptr(&self, ptr: i32) -> Option<usize> {
+        self.blocks.iter().position(|b| b.addr() == ptr)
+    }
+
+    // Finds the block `ptr` was handed out for, if it is still in use.
+    pub fn find_live(&self, ptr: i32) -> Option<usize> {
+        self.find_ptr(ptr)
+            .filter(|i| self.blocks[*i].allocated && self.blocks[*i].cached.is_none())
+    }
+
+    // Finds the block whose bytes, header included, contain `ptr`.
+    pub fn find_containing(&self, ptr: i32) -> Option<usize> {
+        let offset = ptr - self.base;
+        self.blocks.iter().position(|b| b.offset <= offset && offset < b.end())
+    }
+
+    // Works out why `ptr` is not something that can be freed.
+    fn bad_free(&self, ptr: i32) -> HeapError {
+        if self.freed.contains(&ptr) {
+            return HeapError::DoubleFree(ptr);
+        }
+
+        match self.find_containing(ptr) {
+            Some(i) if self.blocks[i].addr() != ptr =>
+                HeapError::InteriorPointer(ptr, self.blocks[i].addr()),
+            Some(_) => HeapError::InvalidPointer(ptr),
+            None => HeapError::OutOfHeap(ptr),
+        }
+    }
+
+    pub fn min_block_size(&self) -> i32 {
+        self.header + 1
+    }
+
+    fn align_down(&self, bytes: i32) -> i32 {
+        bytes - bytes.rem_euclid(self.alignment)
+    }
+
+    // Requests too big to round up can't fit in any heap.
+    pub fn align_up(&self, bytes: i32) -> Result<i32, HeapError> {
+        let bytes_up = bytes.checked_add(self.alignment - 1)
+            .ok_or(HeapError::OutOfMemory(bytes))?;
+        Ok(self.align_down(bytes_up))
+    }
+
+    // Size of the block a request for `bytes` needs, header included.
+    pub fn chunk_size(&self, bytes: i32) -> Result<i32, HeapError> {
+        let size = bytes.checked_add(self.header)
+            .ok_or(HeapError::OutOfMemory(bytes))?;
+        self.align_up(size.max(self.min_block_size()))
+    }
+
+    // Grows the heap by `bytes`, rounded down to the alignment.
+    pub fn sbrk(&mut self, bytes: i32) -> Result<BlockId, HeapError> {
+        let bytes = self.align_down(bytes);
+        if bytes < self.min_block_size() {
+            return Err(HeapError::BelowMinimumSize(self.min_block_size()));
+        }
+        let end = self.end_of_heap_bytes.checked_add(bytes);
+        if end.filter(|end| *end <= self.capacity).is_none() {
+            return Err(HeapError::HeapExhausted(self.capacity));
+        }
+
+        // ptmalloc grows the top chunk rather than adding a block after it.
+        let top = self.top().filter(|_| self.bins.is_some() || self.managed);
+        let id = match top {
+            Some(i) => {
+                self.blocks[i].size += bytes;
+                self.blocks[i].id
+            }
+            None => {
+                let block = self.new_block(
+                    self.end_of_heap_bytes, bytes, Origin::Sbrk);
+                let id = block.id;
+                self.blocks.push(block);
+                id
+            }
+        };
+        self.end_of_heap_bytes += bytes;
+        self.memory.resize(self.end_of_heap_bytes as usize, 0);
+        Ok(id)
+    }
+
+    // Gives `bytes`, rounded down to the alignment, back from the top of the
+    // heap. They must all come out of a free last block, and whatever is
+    // left of it must still be a valid block.
+    pub fn shrink(&mut self, bytes: i32) -> Result<(), HeapError> {
+        let bytes = self.align_down(bytes);
+        let free = match self.blocks.last() {
+            Some(last) if !last.allocated => last.size,
+            _ => 0,
+        };
+        let left = free - bytes;
+        if bytes <= 0 || left < 0 || (left > 0 && left < self.min_block_size()) {
+            return Err(HeapError::CannotShrink(free));
+        }
+
+        if left == 0 {
+            self.blocks.pop();
+        } else if let Some(last) = self.blocks.last_mut() {
+            last.size = left;
+            last.clobbered = last.clobbered.min(left);
+        }
+        self.end_of_heap_bytes -= bytes;
+        self.memory.truncate(self.end_of_heap_bytes as usize);
+        self.sync_bins();
+        Ok(())
+    }
+
+    // Marks the given free block as allocated and returns the pointer to it.
+    pub fn allocate(
+            &mut self, id: BlockId, bytes: i32) -> Result<i32, HeapError> {
+        let ptr = self.hand_out(id, bytes, Origin::Malloc);
+        self.sync_bins();
+        ptr
+    }
+
+    // Allocates `bytes` from the first free block with room for them, or
+    // the way ptmalloc would if it is being emulated.
+    pub fn malloc(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        self.malloc_as(bytes, Origin::Malloc)
+    }
+
+    fn malloc_as(&mut self, bytes: i32, by: Origin) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        match self.bins.take() {
+            Some(mut bins) => {
+                let ptr = self.pt_malloc(&mut bins, bytes, by);
+                self.bins = Some(bins);
+                self.sync_bins();
+                ptr
+            }
+            None => {
+                let target = self.find_fit(bytes)?;
+                if self.managed {
+                    let i = self.index_of(target).ok_or(HeapError::NoSuchBlock)?;
+                    let size = self.chunk_size(bytes)?;
+                    self.carve(i, size)?;
+                }
+                self.hand_out(target, bytes, by)
+            }
+        }
+    }
+
+    // _int_malloc, minus the tcache, which sits in front of the heap:
+    // exact fits from the fast and small bins, then a pass over the unsorted
+    // bin that sorts whatever doesn't fit exactly, then the best fit from the
+    // small and large bins, and finally the top chunk.
+    fn pt_malloc(
+            &mut self,
+            bins: &mut Bins,
+            bytes: i32,
+            by: Origin) -> Result<i32, HeapError> {
+        let size = self.chunk_size(bytes)?;
+        let exact = if size <= FASTBIN_MAX {
+            bins.take_fast(size).or_else(|| bins.take_small(size))
+        } else if size < LARGEBIN_MIN {
+            bins.take_small(size)
+        } else {
+            // Large requests merge the fastbins first, so fragments of
+            // small chunks can add up to a big enough one.
+            self.consolidate(bins);
+            None
+        };
+        if let Some(chunk) = exact {
+            return self.hand_out_chunk(chunk, bytes, by);
+        }
+
+        while let Some(chunk) = bins.pop_unsorted() {
+            if chunk.size == size {
+                return self.hand_out_chunk(chunk, bytes, by);
+            }
+            bins.sort(chunk);
+        }
+
+        if let Some(chunk) = bins.take_best(size) {
+            let i = self.find_ptr(chunk.ptr).ok_or(HeapError::NoSuchBlock)?;
+            if let Some(rest) = self.carve(i, size)? {
+                let rest = &self.blocks[rest];
+                bins.push_unsorted(Chunk { ptr: rest.addr(), size: rest.size });
+            }
+            return self.hand_out_chunk(chunk, bytes, by);
+        }
+
+        match self.top() {
+            Some(i) if self.blocks[i].size >= size => {
+                // Whatever is carved off the end stays the top chunk.
+                self.carve(i, size)?;
+                let id = self.blocks[i].id;
+                self.hand_out(id, bytes, by)
+            }
+            _ => Err(HeapError::OutOfMemory(bytes)),
+        }
+    }
+
+    fn hand_out_chunk(
+            &mut self,
+            chunk: Chunk,
+            bytes: i32,
+            by: Origin) -> Result<i32, HeapError> {
+        let i = self.find_ptr(chunk.ptr).ok_or(HeapError::NoSuchBlock)?;
+        let id = self.blocks[i].id;
+        self.hand_out(id, bytes, by)
+    }
+
+    // Cuts a free block down to `size`, splitting the rest off into a new
+    // block if it is big enough to be one. Returns the new block's index.
+    fn carve(&mut self, i: usize, size: i32) -> Result<Option<usize>, HeapError> {
+        let rest = self.blocks[i].size - size;
+        if rest < self.min_block_size() {
+            return Ok(None);
+        }
+        let id = self.blocks[i].id;
+        self.split(id, rest)?;
+        Ok(Some(i + 1))
+    }
+
+    // malloc_consolidate: frees every fastbin chunk for real, merging it
+    // with its free neighbors.
+    fn consolidate(&mut self, bins: &mut Bins) {
+        for chunk in bins.drain_fast() {
+            if let Some(i) = self.find_ptr(chunk.ptr) {
+                self.blocks[i].cached = None;
+                self.blocks[i].allocated = false;
+                self.merge_free(bins, i);
+            }
+        }
+    }
+
+    // Merges the free block at `i` with free neighbors, taking them out of
+    // their bins, and puts the result in the unsorted bin unless it ended up
+    // as the top chunk.
+    fn merge_free(&mut self, bins: &mut Bins, i: usize) {
+        let mut i = i;
+        if i > 0 && !self.blocks[i - 1].header_allocated() {
+            bins.remove(self.blocks[i - 1].addr());
+            if self.coalesce(i as i64 - 1, i as i64).is_ok() {
+                i -= 1;
+            }
+        }
+        if i + 1 < self.blocks.len() && !self.blocks[i + 1].header_allocated() {
+            bins.remove(self.blocks[i + 1].addr());
+            let _ = self.coalesce(i as i64, i as i64 + 1);
+        }
+        if i + 1 < self.blocks.len() {
+            let block = &self.blocks[i];
+            bins.push_unsorted(Chunk { ptr: block.addr(), size: block.size });
+        }
+    }
+
+    fn find_fit(&mut self, bytes: i32) -> Result<BlockId, HeapError> {
+        let rover = self.rover;
+        let mut fits = self.blocks.iter()
+            .filter(|b| !b.allocated && b.payload() >= bytes);
+        let found = match self.fit {
+            Fit::First => fits.next(),
+            Fit::Next => {
+                let fits: Vec<&Block> = fits.collect();
+                fits.iter().find(|b| b.offset >= rover)
+                    .or_else(|| fits.first())
+                    .cloned()
+            }
+            Fit::Best => fits.min_by_key(|b| b.size),
+            Fit::Worst => fits.max_by_key(|b| b.size),
+        };
+        let block = found.ok_or(HeapError::OutOfMemory(bytes))?;
+        let id = block.id;
+        self.rover = block.offset;
+        Ok(id)
+    }
+
+    fn hand_out(
+            &mut self,
+            id: BlockId,
+            bytes: i32,
+            by: Origin) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+
+        let idx = self.index_of(id).ok_or(HeapError::NoSuchBlock)?;
+        self.trust_header(idx);
+        let block = &mut self.blocks[idx];
+        if bytes > block.payload() {
+            return Err(HeapError::BlockTooSmall(block.payload()));
+        }
+
+        block.allocated = true;
+        block.space_used = bytes;
+        block.allocation = Some((self.next_allocation, by));
+        block.cached = None;
+        self.next_allocation += 1;
+        let ptr = block.addr();
+        self.freed.retain(|p| *p != ptr);
+        Ok(ptr)
+    }
+
+    pub fn free(&mut self, ptr: i32) -> Result<BlockId, HeapError> {
+        let i = self.find_live(ptr).ok_or_else(|| self.bad_free(ptr))?;
+        self.trust_header(i);
+        let block = &mut self.blocks[i];
+        let id = block.id;
+        block.allocated = false;
+        block.space_used = 0;
+        block.allocation = None;
+        self.freed.push(ptr);
+
+        if self.managed && self.bins.is_none() {
+            let mut i = i;
+            if i > 0 && self.coalesce(i as i64 - 1, i as i64).is_ok() {
+                i -= 1;
+            }
+            let _ = self.coalesce(i as i64, i as i64 + 1);
+        }
+
+        // ptmalloc leaves small chunks in use in a fastbin, and merges the
+        // rest with their neighbors straight away.
+        if let Some(mut bins) = self.bins.take() {
+            let size = self.blocks[i].size;
+            if size <= FASTBIN_MAX {
+                self.blocks[i].allocated = true;
+                self.blocks[i].cached = Some(Cache::Fastbin);
+                bins.push_fast(Chunk { ptr: ptr, size: size });
+            } else {
+                self.merge_free(&mut bins, i);
+            }
+            self.bins = Some(bins);
+        }
+        Ok(id)
+    }
+
+    // Frees `ptr` into `thread`'s tcache. The block keeps its in-use bit and
+    // only the tcache knows it is free. Returns the block's size.
+    pub fn cache(&mut self, ptr: i32, thread: usize) -> Result<i32, HeapError> {
+        let i = self.find_live(ptr).ok_or_else(|| self.bad_free(ptr))?;
+        let block = &mut self.blocks[i];
+        block.cached = Some(Cache::Tcache(thread));
+        block.space_used = 0;
+        block.allocation = None;
+        self.freed.push(ptr);
+        Ok(block.size)
+    }
+
+    // Hands out a block that a tcache gave back. A block that is already in
+    // use can only come out of a tcache that was corrupted by a double free,
+    // and ends up handed out twice.
+    pub fn take_cached(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        let i = match self.find_ptr(ptr) {
+            Some(i) if self.blocks[i].allocated => i,
+            _ => return Err(self.bad_free(ptr)),
+        };
+        if self.blocks[i].cached.is_none() {
+            self.blocks[i].corrupted = true;
+        }
+        let id = self.blocks[i].id;
+        let ptr = self.hand_out(id, bytes, Origin::Tcache);
+        self.sync_bins();
+        ptr
+    }
+
+    // Applies what an allocator without any sanity checks would do with a
+    // bad free, and describes the damage.
+    pub fn simulate_bad_free(&mut self, err: &HeapError) -> Option<String> {
+        match *err {
+            HeapError::DoubleFree(ptr) => {
+                let i = self.find_containing(ptr - self.header)?;
+                self.blocks[i].corrupted = true;
+                Some(format!(
+                    "Simulated: the block at {} is on the free list twice.",
+                    self.blocks[i].addr()))
+            }
+            HeapError::InteriorPointer(ptr, _) => {
+                let i = self.find_containing(ptr)?;
+                let bogus_offset = ptr - self.base - self.header;
+                let kept = bogus_offset - self.blocks[i].offset;
+                if kept < self.min_block_size() {
+                    self.blocks[i].corrupted = true;
+                    return Some(String::from(
+                        "Simulated: the block's header was overwritten."));
+                }
+
+                let bogus_size = self.blocks[i].end() - bogus_offset;
+                let mut bogus = self.new_block(bogus_offset, bogus_size, Origin::BadFree);
+                bogus.corrupted = true;
+                self.blocks[i].size = kept;
+                self.blocks[i].space_used =
+                    self.blocks[i].space_used.min(self.blocks[i].payload());
+                self.blocks.insert(i + 1, bogus);
+                self.freed.push(ptr);
+                self.sync_bins();
+                Some(format!(
+                    "Simulated: a bogus {} byte free block now starts at {}.",
+                    bogus_size, bogus_offset + self.base))
+            }
+            _ => None,
+        }
+    }
+
+    // Grows or shrinks the allocation at `ptr`, first in place, then by
+    // absorbing a free right neighbor, and finally by moving it to the first
+    // free block that fits.
+    pub fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+
+        let idx = self.find_live(ptr).ok_or_else(|| self.bad_free(ptr))?;
+
+        if bytes <= self.blocks[idx].payload() {
+            self.blocks[idx].space_used = bytes;
+            return Ok(ptr);
+        }
+
+        let next_free = self.blocks.get(idx + 1).map_or(0, |next| {
+            if next.allocated { 0 } else { next.size }
+        });
+        if bytes <= self.blocks[idx].payload() + next_free {
+            self.blocks[idx].size += next_free;
+            self.blocks[idx].space_used = bytes;
+            self.blocks.remove(idx + 1);
+            if self.managed {
+                let size = self.chunk_size(bytes)?;
+                self.carve(idx, size)?;
+            }
+            self.sync_bins();
+            return Ok(ptr);
+        }
+
+        // Carry the contents over before the old block can be reused.
+        let used = self.blocks[idx].space_used.min(bytes);
+        let new_ptr = self.malloc_as(bytes, Origin::Realloc)?;
+        let (from, to) = ((ptr - self.base) as usize, (new_ptr - self.base) as usize);
+        let end = self.memory.len();
+        let count = (used.max(0) as usize).min(end.saturating_sub(from.max(to)));
+        self.memory.copy_within(from..from + count, to);
+        self.free(ptr)?;
+        Ok(new_ptr)
+    }
+
+    // Writes `data` starting at the block's pointer. Anything past the
+    // payload spills into the following blocks, and every header it reaches
+    // is overwritten with `forged_header`. Returns the number of bytes that
+    // spilled out of the block.
+    pub fn write(
+            &mut self,
+            id: BlockId,
+            data: &[u8],
+            forged_header: i32) -> Result<i32, HeapError> {
+        let idx = self.index_of(id).ok_or(HeapError::NoSuchBlock)?;
+        if !self.blocks[idx].allocated {
+            return Err(HeapError::NotAllocated);
+        }
+
+        let start = (self.blocks[idx].addr() - self.base) as usize;
+        for (dst, src) in self.memory.iter_mut().skip(start).zip(data) {
+            *dst = *src;
+        }
+
+        let bytes = data.len() as i32;
+        let payload = self.blocks[idx].payload();
+        let used = &mut self.blocks[idx].space_used;
+        *used = (*used).max(bytes.min(payload));
+
+        let spilled = (bytes - payload).max(0);
+        let mut remaining = spilled;
+        for block in self.blocks.iter_mut().skip(idx + 1) {
+            if remaining <= 0 {
+                break;
+            }
+            let written = remaining.min(block.size);
+            block.clobbered = block.clobbered.max(written);
+            block.forged = Some(ForgedHeader::from_raw(forged_header));
+            remaining -= written;
+        }
+        Ok(spilled)
+    }
+
+    // The bytes of a block as the allocator and program see them, labelled
+    // by what they are for. Headers hold the size with the in-use flag in the
+    // low bit, and free blocks keep a pointer to the next free block at the
+    // start of their payload. Under ptmalloc the header is prev_size then
+    // size, whose low bit is PREV_INUSE, and binned chunks hold 8 byte fd and
+    // bk pointers.
+    pub fn dump(&self, idx: usize) -> Vec<(u8, ByteKind)> {
+        let block = &self.blocks[idx];
+        let payload = block.payload().max(0) as usize;
+        let (header, free_ptr) = match &self.bins {
+            Some(bins) => {
+                let prev = if idx > 0 { self.blocks.get(idx - 1) } else { None };
+                let prev_free = prev.is_some_and(|b| !b.header_allocated());
+                let prev_size = prev.filter(|_| prev_free).map_or(0, |b| b.size);
+                let size = match block.forged {
+                    Some(header) => header.size | header.allocated as i32,
+                    None => block.size | !prev_free as i32,
+                };
+                let half = block.header / 2;
+                let mut header = le_bytes(prev_size, half);
+                header.extend(le_bytes(size, block.header - half));
+
+                let mut links = vec![];
+                if let Some((fd, bk)) = bins.links(block.addr()) {
+                    links.extend(le_bytes(fd, 8));
+                    if let Some(bk) = bk {
+                        links.extend(le_bytes(bk, 8));
+                    }
+                }
+                links.truncate(payload);
+                (header, links)
+            }
+            None => {
+                let raw_header = match block.forged {
+                    Some(header) => header.size | header.allocated as i32,
+                    None => block.size | block.allocated as i32,
+                };
+                let next_free = self.blocks.iter().skip(idx + 1)
+                    .find(|b| !b.allocated)
+                    .map_or(0, |b| b.addr());
+                let free_ptr = if block.allocated {
+                    vec![]
+                } else {
+                    le_bytes(next_free, 4.min(block.payload()))
+                };
+                (le_bytes(raw_header, block.header), free_ptr)
+            }
+        };
+
+        (0..block.size).map(|i| {
+            // A forged header can stretch the block past what's been sbrk-ed.
+            let byte = self.memory.get((block.offset + i) as usize).cloned().unwrap_or(0);
+            let payload_i = i - block.header;
+            let entry = if i < block.header {
+                (header[i as usize], ByteKind::Header)
+            } else if payload_i < free_ptr.len() as i32 {
+                (free_ptr[payload_i as usize], ByteKind::FreeListPtr)
+            } else if block.allocated && payload_i < block.space_used {
+                (byte, ByteKind::Payload)
+            } else {
+                (byte, ByteKind::Padding)
+            };
+
+            if i < block.clobbered {
+                (entry.0, ByteKind::Clobbered)
+            } else {
+                entry
+            }
+        }).collect()
+    }
+
+    // Splits `bytes`, rounded up to the alignment, off the end of a block
+    // into a new free block.
+    pub fn split(&mut self, id: BlockId, bytes: i32) -> Result<BlockId, HeapError> {
+        let (idx, bytes) = self.check_split(id, bytes)?;
+        let block = &self.blocks[idx];
+        let mut new_block = self.new_block(block.end() - bytes, bytes, Origin::Split);
+        let new_id = new_block.id;
+        self.blocks[idx].size -= bytes;
+
+        // Overwritten bytes stay overwritten, whichever half they land in.
+        let kept = self.blocks[idx].size;
+        new_block.clobbered = (self.blocks[idx].clobbered - kept).max(0);
+        self.blocks[idx].clobbered = self.blocks[idx].clobbered.min(kept);
+        self.blocks.insert(idx + 1, new_block);
+        self.sync_bins();
+        Ok(new_id)
+    }
+
+    // Whether `split` would succeed, without splitting. Returns the block's
+    // index and the aligned number of bytes that would be split off.
+    pub fn check_split(
+            &self, id: BlockId, bytes: i32) -> Result<(usize, i32), HeapError> {
+        let idx = self.index_of(id).ok_or(HeapError::NoSuchBlock)?;
+        let block = &self.blocks[idx];
+        let bytes = self.align_up(bytes)
+            .map_err(|_| HeapError::SplitTooLarge(block.size))?;
+        if bytes < self.min_block_size() {
+            return Err(HeapError::BelowMinimumSize(self.min_block_size()));
+        }
+
+        // Both halves need at least a byte of payload.
+        if block.allocated {
+            let needed = block.header + block.space_used.max(1);
+            if block.size - bytes < needed {
+                return Err(HeapError::SplitWouldClobber(needed));
+            }
+        } else if block.size - bytes < self.min_block_size() {
+            return Err(HeapError::SplitTooLarge(block.size));
+        }
+        Ok((idx, bytes))
+    }
+
+    pub fn coalesce_left(&mut self, id: BlockId) -> Result<(), HeapError> {
+        let idx = self.index_of(id).ok_or(HeapError::NoSuchBlock)? as i64;
+        self.coalesce(idx - 1, idx)?;
+        self.sync_bins();
+        Ok(())
+    }
+
+    pub fn coalesce_right(&mut self, id: BlockId) -> Result<(), HeapError> {
+        let idx = self.index_of(id).ok_or(HeapError::NoSuchBlock)? as i64;
+        self.coalesce(idx, idx + 1)?;
+        self.sync_bins();
+        Ok(())
+    }
+
+    // Merges the block at idx2 into the block at idx1, which keeps its id.
+    fn coalesce(&mut self, idx1: i64, idx2: i64) -> Result<(), HeapError> {
+        if idx1 < 0 || idx2 > (self.blocks.len() as i64 - 1) {
+            return Err(HeapError::NoFreeNeighbor);
+        }
+
+        // Only the headers are consulted, so a forged header can talk the
+        // allocator into merging a block that is still in use.
+        let i1 = idx1 as usize;
+        let i2 = idx2 as usize;
+        if self.blocks[i1].header_allocated() ||
+                self.blocks[i2].header_allocated() {
+            return Err(HeapError::NoFreeNeighbor);
+        }
+
+        self.trust_header(i1);
+        let absorbed = self.blocks.remove(i2);
+        let limit = self.end_of_heap_bytes - self.blocks[i1].offset;
+        let merged = &mut self.blocks[i1];
+        merged.size = (merged.size + absorbed.header_size())
+            .min(limit)
+            .max(merged.header + 1);
+        if merged.allocated || absorbed.allocated || absorbed.forged.is_some() {
+            merged.corrupted = true;
+        }
+        merged.allocated = false;
+        merged.space_used = 0;
+        merged.allocation = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn realloc_carries_the_payload_along() {
+        let mut heap = Heap::with_geometry(0x1000, 16, 16, 1 << 12);
+        heap.managed = true;
+        heap.sbrk(256).unwrap();
+        let ptr = heap.malloc(16).unwrap();
+        heap.malloc(16).unwrap();
+        let id = heap.blocks[heap.find_live(ptr).unwrap()].id;
+        heap.write(id, b"hello", 0).unwrap();
+
+        let moved = heap.realloc(ptr, 64).unwrap();
+        assert_ne!(moved, ptr);
+        let dump = heap.dump(heap.find_live(moved).unwrap());
+        let payload: Vec<u8> = dump.iter()
+            .filter(|(_, kind)| *kind == ByteKind::Payload)
+            .map(|(byte, _)| *byte)
+            .collect();
+        assert_eq!(&payload[..5], b"hello");
+    }
+
+    #[test]
+    fn huge_requests_fail_instead_of_overflowing() {
+        for ptmalloc in [false, true].iter() {
+            let mut heap = Heap::with_geometry(0x1000, 16, 16, 1 << 12);
+            heap.managed = true;
+            if *ptmalloc {
+                heap.bins = Some(Bins::new());
+            }
+            heap.sbrk(256).unwrap();
+            assert!(matches!(heap.malloc(i32::MAX - 8), Err(HeapError::OutOfMemory(_))));
+            assert!(matches!(heap.sbrk(i32::MAX), Err(HeapError::HeapExhausted(_))));
+            let ptr = heap.malloc(16).unwrap();
+            assert!(matches!(
+                heap.realloc(ptr, i32::MAX - 8), Err(HeapError::OutOfMemory(_))));
+            let id = heap.blocks[0].id;
+            assert!(matches!(
+                heap.split(id, i32::MAX), Err(HeapError::SplitTooLarge(_))));
+        }
+    }
+}
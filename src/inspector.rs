@@ -0,0 +1,168 @@
+use malloc_vis::allocator::BlockDescription;
+use malloc_vis::heap::{ByteKind, Heap};
+use crate::objects::draw_button;
+use crate::text::Text;
+
+use quicksilver::{
+    Result,
+    geom::{Rectangle, Vector},
+    graphics::{Background::Col, Color},
+    lifecycle::Window
+};
+
+static BYTES_PER_ROW: usize = 16;
+static FONT_SIZES: [f32; 4] = [12.0, 16.0, 20.0, 24.0];
+
+// Width of the "001000  " address column, in characters.
+static ADDR_CHARS: usize = 8;
+
+pub struct Inspector {
+    pub panel: Rectangle,
+    pub zoom_in_button: Rectangle,
+    pub zoom_out_button: Rectangle,
+    pub fill_button: Rectangle,
+
+    zoom: usize,
+    first_row: usize,
+}
+
+static LEGEND: [(ByteKind, &str); 5] = [
+    (ByteKind::Header, "header"),
+    (ByteKind::Payload, "payload"),
+    (ByteKind::Padding, "padding"),
+    (ByteKind::FreeListPtr, "free list"),
+    (ByteKind::Clobbered, "clobbered"),
+];
+
+pub fn byte_color(kind: ByteKind) -> Color {
+    match kind {
+        ByteKind::Header => Color::WHITE.with_red(0.8).with_green(0.8).with_blue(0.8),
+        ByteKind::Payload => Color::WHITE
+            .with_red(244.0/256.0)
+            .with_blue(113.0/256.0)
+            .with_green(66.0/256.0),
+        ByteKind::Padding => Color::RED.with_alpha(0.5),
+        ByteKind::FreeListPtr => Color::CYAN,
+        ByteKind::Clobbered => Color::YELLOW,
+    }
+}
+
+impl Inspector {
+    pub fn new(pos: Vector, size: Vector) -> Self {
+        let button_size = Vector::new(40, 30);
+        let panel = Rectangle::new(pos, size);
+
+        Inspector {
+            panel: panel,
+            zoom_in_button: Rectangle::new(pos, button_size),
+            zoom_out_button: Rectangle::new(
+                pos + Vector::new(50, 0), button_size),
+            fill_button: Rectangle::new(
+                pos + Vector::new(100, 0), Vector::new(80, 30)),
+            zoom: 1,
+            first_row: 0,
+        }
+    }
+
+    pub fn zoom_in(&mut self) {
+        if self.zoom + 1 < FONT_SIZES.len() {
+            self.zoom += 1;
+        }
+    }
+
+    pub fn zoom_out(&mut self) {
+        if self.zoom > 0 {
+            self.zoom -= 1;
+        }
+    }
+
+    pub fn scroll(&mut self, rows: i32) {
+        self.first_row = (self.first_row as i32 + rows).max(0) as usize;
+    }
+
+    fn font_size(&self) -> f32 {
+        FONT_SIZES[self.zoom]
+    }
+
+    fn row_text(start: i32, bytes: &[(u8, ByteKind)]) -> String {
+        let hex: Vec<String> = bytes.iter()
+            .map(|(b, _)| format!("{:02x}", b))
+            .collect();
+        let ascii: String = bytes.iter()
+            .map(|(b, _)| {
+                if b.is_ascii_graphic() { *b as char } else { '.' }
+            })
+            .collect();
+        format!("{:06x}  {:<47}  {}", start, hex.join(" "), ascii)
+    }
+
+    pub fn draw(
+            &mut self,
+            window: &mut Window,
+            text: &mut Text,
+            heap: &Heap,
+            block: &BlockDescription) -> Result<()> {
+        window.draw(&self.panel, Col(Color::WHITE.with_alpha(0.9)));
+        draw_button(self.zoom_in_button, "+", text, window)?;
+        draw_button(self.zoom_out_button, "-", text, window)?;
+        if block.in_use() {
+            draw_button(self.fill_button, "fill", text, window)?;
+        }
+
+        let mut legend_x = self.fill_button.x() + self.fill_button.width() + 10.0;
+        let legend_y = self.panel.y() + 15.0;
+        for (kind, name) in LEGEND.iter() {
+            window.draw(
+                &Rectangle::new((legend_x, legend_y - 6.0), (12, 12)),
+                Col(byte_color(*kind)));
+            legend_x += 16.0;
+            text.draw(
+                window, name, Vector::new(legend_x, legend_y - 8.0),
+                16.0, Color::BLACK)?;
+            legend_x += Text::width(name, 16.0) + 12.0;
+        }
+
+        let idx = match heap.index_of(block.id) {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        let dump = heap.dump(idx);
+        let start = heap.base + block.offset;
+
+        let font_size = self.font_size();
+        let char_width = font_size / 2.0;
+        let row_height = font_size * 1.25;
+        let top = self.panel.y() + 40.0;
+        let visible_rows =
+            ((self.panel.y() + self.panel.height() - top) / row_height) as usize;
+        let total_rows = dump.len().div_ceil(BYTES_PER_ROW);
+        self.first_row = self.first_row.min(total_rows.saturating_sub(1));
+        let last_row = total_rows.min(self.first_row + visible_rows);
+
+        for (slot, row) in (self.first_row..last_row).enumerate() {
+            let bytes = &dump[row * BYTES_PER_ROW..
+                ((row + 1) * BYTES_PER_ROW).min(dump.len())];
+            let row_start = start + (row * BYTES_PER_ROW) as i32;
+            let y = top + (slot as f32) * row_height;
+
+            for (i, (_, kind)) in bytes.iter().enumerate() {
+                let cell_x = self.panel.x() + 10.0
+                    + ((ADDR_CHARS + 3 * i) as f32) * char_width;
+                window.draw(
+                    &Rectangle::new(
+                        (cell_x - char_width / 2.0, y),
+                        (3.0 * char_width, row_height)),
+                    Col(byte_color(*kind)));
+            }
+
+            let row_text = Inspector::row_text(row_start, bytes);
+            text.draw(
+                window,
+                &row_text,
+                Vector::new(self.panel.x() + 10.0, y + font_size * 0.125),
+                font_size,
+                Color::BLACK)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+// The allocator model without any of the graphics, shared by the
+// visualization and the tools that run it from the command line.
+// Constructors spell out every field, `offset: offset` and all.
+#![allow(clippy::redundant_field_names)]
+
+pub mod allocator;
+pub mod bins;
+pub mod bump;
+pub mod checker;
+pub mod config;
+pub mod constants;
+pub mod heap;
+pub mod metrics;
+pub mod pages;
+#[cfg(test)]
+mod props;
+pub mod svg;
+pub mod tcache;
+pub mod trace;
+pub mod workload;
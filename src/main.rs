@@ -1,56 +1,122 @@
+#![allow(clippy::redundant_field_names)]
+
 extern crate quicksilver;
 use quicksilver::{
-    Future, Result,
-    combinators::result,
+    Result,
     geom::{Shape, Rectangle, Vector},
-    graphics::{Background::Col, Background::Img, Color, Font, FontStyle, Image},
-    input::{MouseButton, ButtonState},
-    lifecycle::{Asset, Event, Settings, State, Window, run}
+    graphics::{Background::Col, Color},
+    input::{Key, MouseButton, ButtonState},
+    lifecycle::{Event, Settings, State, Window, run}
 };
 
 #[macro_use]
 extern crate stdweb;
 use stdweb::unstable::TryInto;
 
-use core::i32::MAX;
-
 mod objects;
-mod constants;
+mod page_grid;
+mod animation;
+mod arena;
+mod compare;
+mod inspector;
+mod ruler;
+mod text;
+mod view;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+use malloc_vis::allocator::{BlockDescription, BlockState};
+use malloc_vis::config::*;
+use malloc_vis::heap::*;
+use malloc_vis::svg::Frame;
+use malloc_vis::tcache::*;
+use malloc_vis::trace::{Trace, TraceRunner};
+use malloc_vis::workload::Workload;
+use crate::arena::*;
+use crate::compare::Comparison;
 use crate::objects::*;
-use crate::constants::*;
-
-pub fn draw_num(
-        font_num_map: &mut Asset<Image>,
-        font_size: &Vector,
-        num: i32,
-        start: &Vector,
-        window: &mut Window
-    ) -> Result<()> {
-    let size_str: String = num.to_string();
-    let x = font_size.x;
-    let y = font_size.y;
-    font_num_map.execute(|image| {
-        for (i, c) in size_str.chars().enumerate() {
-            let index = ((c as i32) - ('0' as i32)) as f32;
-            let subimg = &image.subimage(
-                Rectangle::new((index*x, 0), (x, y)));
-            let x_off = start.x + (i as f32)*x + x/2.0;
-            window.draw(&subimg.area()
-                            .with_center((x_off, start.y + y/2.0)),
-                        Img(&subimg));
-        }
-        Ok(())
-    })
-}
+use crate::inspector::*;
+use crate::page_grid::PageGrid;
+use crate::ruler::*;
+use crate::text::Text;
+use crate::view::*;
 
 struct MallocState {
-    allocations: Vec<Block>,
+    config: Config,
+    arenas: Vec<Arena>,
+    // The arena that selections and operations apply to.
+    current: usize,
+    // Simulated thread id. Each thread allocates from its own arena, and
+    // threads share arenas once there are more threads than arenas.
+    thread: usize,
+    // One per simulated thread, indexed by thread id.
+    tcaches: Vec<Tcache>,
+    // Where the last malloc was served from.
+    malloc_status: String,
+    // Set when the heap is shown as size-class pages, which then take the
+    // place of the arenas.
+    pages: Option<PageGrid>,
+    // The trace loaded on the page, stepped through with n.
+    trace: Option<TraceRunner>,
+    // The same, played on every arena at once when comparing policies.
+    comparison: Option<Comparison>,
     alloc_menu: AllocationMenu,
-    sbrk_obj: SbrkDescriptor,
-    display_menu: Option<usize>,
+    inspector: Inspector,
+    text: Text,
+    split_handle: DragHandle,
+    // The block the split handle was last placed in.
+    split_block: Option<BlockId>,
+    display_menu: Option<BlockId>,
+    diagnostic: Option<Diagnostic>,
+    hovered: Option<(usize, BlockId)>,
+    tooltip: Tooltip,
+    show_shortcuts: bool,
+    simulate_corruption: bool,
 }
 
+// How far the heap grows or shrinks per press of + or -.
+static SBRK_STEP: i32 = 64;
+
+// Height of the bin list panel shown under the menu under ptmalloc, in
+// menu units.
+static BINS_PANEL_UNITS: i32 = 2;
+
+// Thread ids cycle through this many threads.
+static MAX_THREADS: usize = 8;
+
 impl MallocState {
+    fn heap(&self) -> &Heap {
+        &self.arenas[self.current].heap
+    }
+
+    fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.arenas[self.current].heap
+    }
+
+    fn view(&self) -> &View {
+        &self.arenas[self.current].view
+    }
+
+    // The block the menu is open for, if it still exists.
+    fn selected(&self) -> Option<BlockDescription> {
+        self.display_menu.and_then(|id| self.arenas[self.current].block(id))
+    }
+
+    // Makes `index` the current arena, dropping any selection in the old one.
+    fn switch_arena(&mut self, index: usize) {
+        if index != self.current {
+            self.current = index;
+            self.display_menu = None;
+        }
+    }
+
+    // Frees and reallocs go to whichever arena the pointer belongs to, as
+    // glibc finds a chunk's arena from its address.
+    fn route(&mut self, ptr: i32) {
+        if let Some(i) = self.arenas.iter().position(|a| a.heap.owns(ptr)) {
+            self.switch_arena(i);
+        }
+    }
+
     fn alert_user(msg: &str) {
         js! {
             alert(@{msg});
@@ -62,313 +128,919 @@ impl MallocState {
             var input = prompt(@{prompt});
             var num_input = Number(input);
             if (isNaN(num_input) == NaN)
-                return @{MAX};
-            if (num_input > @{MAX})
-                return @{MAX};
+                return @{i32::MAX};
+            if (num_input > @{i32::MAX})
+                return @{i32::MAX};
             return num_input;
         };
         value
     }
 
-    fn do_allocate(&mut self, idx: usize) {
+    fn get_user_text(prompt: &str) -> String {
+        let value = js! {
+            var input = prompt(@{prompt});
+            return input === null ? "" : input;
+        };
+        value.into_string().unwrap_or_default()
+    }
+
+    // Reads settings from the page's query string, or from CONFIG_FILE when
+    // running natively.
+    fn load_config() -> (Config, std::result::Result<(), String>) {
+        let mut config = Config::default();
+        let loaded = if cfg!(target_arch = "wasm32") {
+            let query = js! {
+                return window.location.search.substring(1);
+            };
+            config.parse_query(&query.into_string().unwrap_or_default())
+        } else {
+            let contents =
+                std::fs::read_to_string(CONFIG_FILE).unwrap_or_default();
+            config.parse(&contents, '\n')
+        };
+
+        (config, loaded)
+    }
+
+    // Lets the user edit the settings, then reloads the page with them.
+    fn do_settings(&mut self) {
+        let current = self.config.to_query();
+        js! {
+            var input = prompt(
+                concat!("Settings (capacity, header, align, arenas, tcache,",
+                    " tcache_max, ptmalloc, pages, page_size, allocator, compare,",
+                    " workload,",
+                    " zoom, menu, gaps, row, rows,",
+                    " speed, width, height)"),
+                @{current});
+            if (input !== null)
+                window.location.search = input;
+        }
+    }
+
+    // Looks for broken invariants after an operation. Frees and reallocs
+    // are routed by address, so any arena may be the one that changed.
+    fn check_heaps(&mut self) {
+        for arena in self.arenas.iter_mut() {
+            arena.check();
+        }
+    }
+
+    fn report<T>(result: std::result::Result<T, HeapError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                MallocState::alert_user(&err.to_string());
+                None
+            }
+        }
+    }
+
+    fn do_allocate(&mut self, id: BlockId) {
         let bytes: i32 = MallocState::get_user_input(
             "Enter number of bytes to be used")
                 .try_into().unwrap();
+        MallocState::report(self.heap_mut().allocate(id, bytes));
+        self.check_heaps();
+    }
 
+    // Serves a malloc from the thread's tcache when it has a block of the
+    // right size, and from the current arena otherwise.
+    fn do_malloc(&mut self) {
+        let bytes: i32 = MallocState::get_user_input(
+            "Enter number of bytes to malloc")
+                .try_into().unwrap();
         if bytes < 0 {
-            MallocState::alert_user(
-                "Must allocate a positive amount.");
+            MallocState::alert_user(&HeapError::NegativeSize.to_string());
+            return;
+        }
+        // A number too big to type in comes back as i32::MAX. No arena
+        // could hold more than its capacity anyway.
+        if bytes > self.config.heap_capacity {
+            MallocState::alert_user(&HeapError::OutOfMemory(bytes).to_string());
             return;
         }
 
-        let block_size: i32 =
-            self.allocations[idx].rect.width() as i32 + MEM_GAP;
-        if bytes <= block_size {
-            self.allocations[idx].allocated = true;
-            self.allocations[idx].space_used = bytes;
-        } else {
-            MallocState::alert_user(
-                "The block is too small to store that amount!");
+        if let Some(grid) = &mut self.pages {
+            if let Some((ptr, page)) = MallocState::report(grid.heap.malloc(bytes)) {
+                self.malloc_status = format!(
+                    "malloc({}) = {} from page {}", bytes, hex(ptr), page);
+            }
+            return;
+        }
+
+        let arena = &mut self.arenas[self.current];
+        if arena.plugin.is_some() {
+            let source = arena.allocator().name();
+            if let Some(ptr) = MallocState::report(arena.allocator_mut().malloc(bytes)) {
+                self.malloc_status = format!(
+                    "malloc({}) = {} from {}", bytes, hex(ptr), source);
+            }
+            return;
+        }
+
+        let size = match MallocState::report(self.heap().chunk_size(bytes)) {
+            Some(size) => size,
+            None => return,
+        };
+        let thread = self.thread;
+        let (result, source) = match self.tcaches[thread].take(size) {
+            Some(ptr) => {
+                self.route(ptr);
+                (self.heap_mut().take_cached(ptr, bytes),
+                    format!("thread {}'s tcache", thread))
+            }
+            None => (self.heap_mut().malloc(bytes), Arena::name(self.current)),
+        };
+        if let Some(ptr) = MallocState::report(result) {
+            self.malloc_status = format!(
+                "malloc({}) = {} from {}", bytes, hex(ptr), source);
+            self.display_menu = self.heap().blocks.iter()
+                .find(|b| b.addr() == ptr)
+                .map(|b| b.id);
+        }
+        self.check_heaps();
+    }
+
+    // Small blocks go into the thread's tcache until their bin is full, and
+    // only then back to the heap.
+    fn do_free(&mut self, ptr: i32) {
+        if let Some(grid) = &mut self.pages {
+            MallocState::report(grid.heap.free(ptr));
+            return;
+        }
+
+        self.route(ptr);
+        if self.arenas[self.current].plugin.is_some() {
+            let result = self.arenas[self.current].allocator_mut().free(ptr);
+            if let Err(err) = result {
+                self.diagnose(ptr, err);
+            }
+            return;
         }
+
+        let thread = self.thread;
+        let live = self.heap().find_live(ptr).map(|i| self.heap().blocks[i].size);
+        let result = match live {
+            Some(size) if self.tcaches[thread].has_room(size) => {
+                let cached = self.heap_mut().cache(ptr, thread);
+                cached.map(|size| self.tcaches[thread].put(size, ptr))
+            }
+            _ => self.heap_mut().free(ptr).map(|_| ()),
+        };
+
+        if let Err(err) = result {
+            // Without the double free check the block goes into the tcache
+            // a second time, and two mallocs will hand it out.
+            let cached = self.heap().blocks.iter()
+                .find(|b| b.addr() == ptr)
+                .filter(|b| matches!(b.cached, Some(Cache::Tcache(_))))
+                .map(|b| b.size);
+            if let (true, Some(size)) = (self.simulate_corruption, cached) {
+                self.tcaches[thread].put(size, ptr);
+            }
+            self.diagnose(ptr, err);
+        }
+        self.check_heaps();
+    }
+
+    // Explains a bad pointer on screen rather than through an alert, since
+    // the interesting part is where the pointer lands in the heap.
+    fn diagnose(&mut self, ptr: i32, err: HeapError) {
+        let glibc = match err.glibc_message() {
+            Some(msg) => msg,
+            None => {
+                MallocState::alert_user(&err.to_string());
+                return;
+            }
+        };
+
+        let mut text = vec![
+            err.to_string(),
+            format!("glibc: \"{}\", then abort()", glibc),
+        ];
+        text.extend(err.explanation().iter().map(|line| line.to_string()));
+        if self.simulate_corruption {
+            if let Some(outcome) = self.heap_mut().simulate_bad_free(&err) {
+                text.push(outcome);
+            }
+        }
+        text.push(String::from("Click anywhere to dismiss."));
+        self.diagnostic = Some(Diagnostic::new(ptr, text));
+    }
+
+    fn do_free_ptr(&mut self) {
+        let ptr: i32 = MallocState::get_user_input(
+            "Enter pointer to free")
+                .try_into().unwrap();
+        self.do_free(ptr);
     }
 
-    fn do_split(&mut self, idx: usize) {
+    fn do_realloc(&mut self, ptr: i32) {
         let bytes: i32 = MallocState::get_user_input(
-            "Enter number of bytes for split")
+            "Enter new number of bytes to be used")
                 .try_into().unwrap();
-        self.split(idx, bytes);
+        self.route(ptr);
+        match self.heap_mut().realloc(ptr, bytes) {
+            Ok(new_ptr) => {
+                self.display_menu = self.heap().blocks.iter()
+                    .find(|b| b.addr() == new_ptr)
+                    .map(|b| b.id);
+            }
+            Err(err) => { self.diagnose(ptr, err); }
+        }
+        self.check_heaps();
     }
 
-    fn split(&mut self, idx: usize, bytes: i32) {
-        if bytes < MEM_GAP + 1 {
-            MallocState::alert_user(&format!(
-                concat!("Due to rendering constraints,",
-                " the minimum block size is {}"),
-                MEM_GAP + 1)[..]);
+    // Blocks of other allocators can't be selected, so clicking one asks
+    // for its new size straight away, with 0 freeing it like realloc(p, 0).
+    fn do_plugin_realloc(&mut self, ptr: i32) {
+        let bytes: i32 = MallocState::get_user_input(
+            "Enter new number of bytes, or 0 to free")
+                .try_into().unwrap();
+        if bytes == 0 {
+            self.do_free(ptr);
             return;
         }
-        let alloc = self.allocations[idx].rect;
-        let space_used = self.allocations[idx].space_used;
-        let allocated = self.allocations[idx].allocated;
-        let block_size: i32 = alloc.width() as i32 + MEM_GAP;
-        if allocated {
-            if bytes > (block_size - space_used) {
-                MallocState::alert_user(&format!(
-                        concat!("Can't split this block with given new size.",
-                        "This block must have at least {} bytes."),
-                        space_used)[..]);
-                return;
+        let arena = &mut self.arenas[self.current];
+        if let Some(new_ptr) = MallocState::report(arena.allocator_mut().realloc(ptr, bytes)) {
+            self.malloc_status = format!(
+                "realloc({}, {}) = {}", hex(ptr), bytes, hex(new_ptr));
+        }
+    }
+
+    // Runs the next op of the trace loaded on the page in the current arena,
+    // or in all of them when comparing policies. Loading another trace
+    // starts over from its first op. Without one, the trace comes from the
+    // `workload` setting.
+    fn do_trace_step(&mut self) {
+        let loaded = js! {
+            var trace = mallocVis.trace;
+            mallocVis.trace = null;
+            return trace;
+        };
+        let started = self.trace.is_some() || self.comparison.is_some();
+        let parsed = match loaded.into_string() {
+            Some(text) => Some(Trace::parse(&text)),
+            None if !started && !self.config.workload.is_empty() => Some(
+                Workload::parse(&self.config.workload).map(|w| w.generate())),
+            None => None,
+        };
+        if let Some(parsed) = parsed {
+            match parsed {
+                Ok(trace) if !self.config.compared().is_empty() => {
+                    self.comparison = Some(Comparison::new(trace, self.arenas.len()));
+                }
+                Ok(trace) => self.trace = Some(TraceRunner::new(trace)),
+                Err(err) => {
+                    MallocState::alert_user(&err);
+                    return;
+                }
             }
-        } else if bytes > block_size {
-            MallocState::alert_user(&format!(
-                    concat!("Can't split this block with given new size.",
-                    "This block only has {} bytes."),
-                    block_size)[..]);
+        }
+        if self.comparison.is_some() {
+            self.do_compare_step();
             return;
         }
 
-        let new_x = alloc.x() as i32 + block_size - bytes;
-        self.allocations.insert(idx+1, Block {
-            rect: Rectangle::new((new_x, 0), (bytes - MEM_GAP, SBRK_MENU_PX)),
-            allocated: false,
-            space_used: 0,
+        let runner = match &mut self.trace {
+            Some(runner) => runner,
+            None => {
+                MallocState::alert_user(
+                    "Load a trace on the page or set a workload first.");
+                return;
+            }
+        };
+        let total = runner.trace.ops.len();
+        self.malloc_status = match runner.step(self.arenas[self.current].allocator_mut()) {
+            None => String::from("the trace is done"),
+            Some((op, result)) => {
+                let done = format!("op {}/{}: {}", runner.next, total, op);
+                match result {
+                    Ok(Some(ptr)) => format!("{} = {}", done, hex(ptr)),
+                    Ok(None) => done,
+                    Err(err) => format!("{} failed: {}", done, err),
+                }
+            }
+        };
+        if self.selected().is_none() {
+            self.display_menu = None;
+        }
+        self.check_heaps();
+    }
+
+    fn do_compare_step(&mut self) {
+        let comparison = match &mut self.comparison {
+            Some(comparison) => comparison,
+            None => return,
+        };
+        let total = comparison.total();
+        let (op, results) = match comparison.step(&mut self.arenas) {
+            Some(stepped) => stepped,
+            None => {
+                self.malloc_status = String::from("the trace is done");
+                return;
+            }
+        };
+
+        let mut status = format!("op {}/{}: {}", comparison.next(), total, op);
+        let failed: Vec<String> = results.iter().zip(self.arenas.iter())
+            .filter(|(result, _)| result.is_err())
+            .map(|(_, arena)| arena.allocator().name())
+            .collect();
+        if !failed.is_empty() {
+            status = format!("{}, failed under {}", status, failed.join(", "));
+        }
+        if let Some(at) = comparison.diverged_at {
+            status = format!("{}; placements differ since op {}", status, at);
+        }
+        self.malloc_status = status;
+        if self.selected().is_none() {
+            self.display_menu = None;
+        }
+        self.check_heaps();
+    }
+
+    // Saves the current arena as heap.svg, for slides and handouts.
+    fn do_export(&mut self) {
+        let arena = &self.arenas[self.current];
+        let mut title = Arena::name(self.current);
+        if let Some(plugin) = &arena.plugin {
+            title = format!("{} ({})", title, plugin.name());
+        }
+        let mut frame = Frame::of(arena.allocator(), arena.heap.base, &title);
+        frame.stats.push(self.malloc_status.clone());
+        let svg = frame.render(
+            self.config.window_width as f32, self.config.row_bytes);
+        js! {
+            var link = document.createElement("a");
+            link.href = URL.createObjectURL(
+                new Blob([@{svg}], { type: "image/svg+xml" }));
+            link.download = "heap.svg";
+            link.click();
+            URL.revokeObjectURL(link.href);
+        }
+    }
+
+    fn do_write(&mut self, id: BlockId, payload: i32) {
+        let bytes: i32 = MallocState::get_user_input(
+            "Enter number of bytes to write at the pointer")
+                .try_into().unwrap();
+        if bytes < 0 {
+            MallocState::alert_user(&HeapError::NegativeSize.to_string());
+            return;
+        }
+        // Nothing past the end of the heap can be written anyway.
+        let room = self.heap().block(id).map_or(0, |block| {
+            self.heap().end_of_heap_bytes - block.offset - block.header
         });
-        self.allocations[idx].rect = Rectangle::new(
-            (alloc.x(), 0), (alloc.width() as i32 - bytes, SBRK_MENU_PX));
+        self.write_bytes(id, payload, &vec![b'A'; bytes.min(room) as usize]);
     }
 
-    fn coalesce(&mut self, idx1: i64, idx2: i64) -> bool{
-        let i1 = idx1 as usize;
-        let i2 = idx2 as usize;
+    fn do_fill(&mut self, id: BlockId, payload: i32) {
+        let text = MallocState::get_user_text(
+            "Enter text to write at the pointer");
+        self.write_bytes(id, payload, text.as_bytes());
+    }
 
-        let mut can_coalesce = true;
-        if idx1 < 0 {
-            can_coalesce = false;
-        } else if idx2 > (self.allocations.len() as i64 - 1) {
-            can_coalesce = false;
-        } else if self.allocations[i1].allocated ||
-                self.allocations[i2].allocated {
-            can_coalesce = false;
+    fn write_bytes(&mut self, id: BlockId, payload: i32, data: &[u8]) {
+        let bytes = data.len() as i32;
+        let mut forged_header = 0;
+        if bytes > payload {
+            if !self.simulate_corruption {
+                MallocState::alert_user(&format!(
+                    concat!("Writing {} bytes overflows this block's {} byte",
+                    " payload. Turn on simulate to see what happens."),
+                    bytes, payload)[..]);
+                return;
+            }
+            forged_header = MallocState::get_user_input(concat!(
+                "Enter the size to write over the next header",
+                " (the low bit is the in-use bit)"))
+                    .try_into().unwrap();
         }
+        MallocState::report(self.heap_mut().write(id, data, forged_header));
+        self.check_heaps();
+    }
 
-        if !can_coalesce {
-            MallocState::alert_user("Can't coalesce without a free neighbor.");
-            return false;
+    fn do_split(&mut self, id: BlockId) {
+        let bytes: i32 = MallocState::get_user_input(
+            "Enter number of bytes for split")
+                .try_into().unwrap();
+        MallocState::report(self.heap_mut().split(id, bytes));
+        self.check_heaps();
+    }
+
+    fn do_coalesce(&mut self, id: BlockId, left: bool) {
+        let heap = self.heap_mut();
+        let result = if left {
+            heap.coalesce_left(id)
+        } else {
+            heap.coalesce_right(id)
+        };
+        if MallocState::report(result).is_some() {
+            self.display_menu = None;
         }
+        self.check_heaps();
+    }
 
-        let rect1 = self.allocations[i1].rect;
-        let rect2 = self.allocations[i2].rect;
+    // Grows the heap by `bytes`, or shrinks it when negative.
+    fn do_sbrk(&mut self, bytes: i32) {
+        MallocState::report(self.arenas[self.current].sbrk(bytes));
+        if self.selected().is_none() {
+            self.display_menu = None;
+        }
+        self.check_heaps();
+    }
+
+    // Selects `block` and scrolls it into view.
+    fn select(&mut self, block: &BlockDescription, window: &mut Window) {
+        self.display_menu = Some(block.id);
+        let arena = &mut self.arenas[self.current];
+        arena.view.reveal(block.offset as f32, window.screen_size().x);
+    }
+
+    fn handle_key(&mut self, key: Key, window: &mut Window) -> Result<()> {
+        if key == Key::Escape {
+            self.display_menu = None;
+            self.diagnostic = None;
+            self.show_shortcuts = false;
+            return Ok(());
+        }
+        if key == Key::Tab {
+            let next = (self.current + 1) % self.arenas.len();
+            self.switch_arena(next);
+            return Ok(());
+        }
+        // Blocks of other allocators are clicked on rather than selected.
+        let arena = &self.arenas[self.current];
+        let blocks = arena.allocator().describe_blocks();
+        if arena.plugin.is_some() || blocks.is_empty() {
+            return Ok(());
+        }
 
-        self.allocations[i1].rect = Rectangle::new(
-            (rect1.x(), 0),
-            (rect1.width() + (MEM_GAP as f32) + rect2.width(), rect1.height()));
-        self.allocations.remove(i2);
-        return true;
+        let last = blocks.len() - 1;
+        let position = |id| blocks.iter().position(|block| block.id == id);
+        let current = self.display_menu.and_then(position);
+        let target = match (key, current) {
+            (Key::Left, Some(i)) => i.saturating_sub(1),
+            (Key::Right, Some(i)) => (i + 1).min(last),
+            (Key::Left, None) => last,
+            (Key::Right, None) => 0,
+            (Key::Up, _) | (Key::Down, _) => {
+                // Move by a row when wrapped, otherwise by a block.
+                let step = if key == Key::Up { -1 } else { 1 };
+                match current {
+                    Some(i) if arena.view.wrapped() => {
+                        let offset = blocks[i].offset + step * arena.view.row_bytes;
+                        arena.allocator().block_at(offset)
+                            .and_then(|block| position(block.id))
+                            .unwrap_or(i)
+                    }
+                    Some(i) => (i as i32 + step).max(0).min(last as i32) as usize,
+                    None => 0,
+                }
+            }
+            _ => return Ok(()),
+        };
+        self.select(&blocks[target], window);
+        Ok(())
+    }
+
+    fn handle_typed(&mut self, c: char) -> Result<()> {
+        if c == '?' {
+            self.show_shortcuts = !self.show_shortcuts;
+            return Ok(());
+        }
+        if self.pages.is_some() {
+            if c == 'm' {
+                self.do_malloc();
+            }
+            return Ok(());
+        }
+
+        let arena = &self.arenas[self.current];
+        let selected = self.selected()
+            .map(|b| (b.id, b.in_use(), arena.ptr(&b)));
+        match (c, selected) {
+            ('a', Some((id, false, _))) => self.do_allocate(id),
+            ('m', _) => self.do_malloc(),
+            ('f', Some((_, true, ptr))) => self.do_free(ptr),
+            ('s', Some((id, _, _))) => self.do_split(id),
+            ('[', Some((id, false, _))) => self.do_coalesce(id, true),
+            (']', Some((id, false, _))) => self.do_coalesce(id, false),
+            ('+', _) | ('=', _) => self.do_sbrk(SBRK_STEP),
+            ('-', _) => self.do_sbrk(-SBRK_STEP),
+            ('n', _) => self.do_trace_step(),
+            ('e', _) => self.do_export(),
+            ('x', _) => self.simulate_corruption = !self.simulate_corruption,
+            ('p', _) => {
+                let paused = !self.arenas[0].animator.paused;
+                for arena in self.arenas.iter_mut() {
+                    arena.animator.paused = paused;
+                }
+            }
+            ('t', _) => {
+                self.thread = (self.thread + 1) % MAX_THREADS;
+                let arena = self.thread % self.arenas.len();
+                self.switch_arena(arena);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Puts the split handle in the middle of the unused part of a newly
+    // selected block.
+    fn sync_split_handle(&mut self) {
+        if self.split_block == self.display_menu {
+            return;
+        }
+        self.split_block = self.display_menu;
+        self.split_handle.release();
+        if let Some(block) = self.selected() {
+            let unused = block.offset + block.header + block.used;
+            self.split_handle.handle_bytes = (unused + block.end()) as f32 / 2.0;
+        }
+    }
+
+    // Where the split handle would split the selected block, snapped so the
+    // new block is a multiple of the alignment, and whether it may.
+    fn split_preview(&self)
+            -> Option<(BlockId, i32, std::result::Result<(), HeapError>)> {
+        let heap = self.heap();
+        let block = self.split_block.and_then(|id| self.arenas[self.current].block(id))?;
+        let tail = block.end() - self.split_handle.handle_bytes as i32;
+        let point = block.end() - heap.align_up(tail).ok()?;
+        let allowed = heap.check_split(block.id, tail).map(|_| ());
+        Some((block.id, point, allowed))
     }
 
     fn handle_click(
             &mut self, _event: &Event, window: &mut Window) -> Result<()> {
        let mouse_pos = window.mouse().pos();
-       if mouse_pos.overlaps_rectangle(&self.sbrk_obj.sbrk_rect) {
-           self.sbrk_obj.selected = true;
+       if self.diagnostic.is_some() || self.show_shortcuts {
+           self.diagnostic = None;
+           self.show_shortcuts = false;
            return Ok(());
+       }
+       if self.pages.is_some() {
+           return self.handle_page_click(mouse_pos);
+       }
+
+       let arena = &self.arenas[self.current];
+       let selected = self.selected()
+           .map(|b| (b.id, b.in_use(), arena.ptr(&b)));
+       let payload = self.selected().map_or(0, |b| b.size - b.header);
+       self.sync_split_handle();
+       if let Some((_, point, _)) = self.split_preview() {
+           let bar = self.split_handle.bar(point as f32, self.view());
+           if mouse_pos.overlaps_rectangle(&bar) {
+               self.split_handle.selected = true;
+               return Ok(());
+           }
+       }
+
+       let sbrk = self.arenas.iter()
+           .position(|a| mouse_pos.overlaps_rectangle(&a.sbrk_obj.rect(&a.view)));
+       if let Some(i) = sbrk {
+           self.switch_arena(i);
+           self.arenas[i].sbrk_obj.selected = true;
+           return Ok(());
+       }
+
+       if mouse_pos.overlaps_rectangle(&self.alloc_menu.free_ptr_button) {
+           self.do_free_ptr();
+       } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.malloc_button) {
+           self.do_malloc();
+       } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.settings_button) {
+           self.do_settings();
+       } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.simulate_button) {
+           self.simulate_corruption = !self.simulate_corruption;
        } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.allocate_button) {
-           match self.display_menu {
-               Some(i) => {
-                   //self.allocations[i].1 = !self.allocations[i].1;
-                   if self.allocations[i].allocated {
-                       self.allocations[i].allocated = false;
-                       self.allocations[i].space_used = 0;
-                   } else {
-                       self.do_allocate(i);
-                   }
-               }
+           match selected {
+               Some((_, true, ptr)) => { self.do_free(ptr); }
+               Some((id, false, _)) => { self.do_allocate(id); }
                _ => {}
            }
+       } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.realloc_button) {
+           if let Some((_, true, ptr)) = selected {
+               self.do_realloc(ptr);
+           }
        } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.coalesce_left_button) {
-           match self.display_menu {
-               Some(i) => {
-                   if !self.allocations[i].allocated {
-                       if self.coalesce(i as i64 - 1, i as i64) {
-                           self.display_menu = None;
-                       }
-                   }
-               }
-               _ => {}
+           if let Some((id, false, _)) = selected {
+               self.do_coalesce(id, true);
            }
        } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.coalesce_right_button) {
-           match self.display_menu {
-               Some(i) => {
-                    if !self.allocations[i].allocated {
-                       if self.coalesce(i as i64, i as i64 +1) {
-                           self.display_menu = None;
-                       }
-                   } else { }
-               }
-               _ => {}
+           if let Some((id, false, _)) = selected {
+               self.do_coalesce(id, false);
+           }
+       } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.write_button) {
+           if let Some((id, true, _)) = selected {
+               self.do_write(id, payload);
            }
        } else if mouse_pos.overlaps_rectangle(&self.alloc_menu.split_button) {
-            match self.display_menu {
-               Some(i) => { self.do_split(i); }
-               _ => {}
+           if let Some((id, _, _)) = selected {
+               self.do_split(id);
+           }
+       } else if selected.is_some() &&
+               mouse_pos.overlaps_rectangle(&self.inspector.panel) {
+           if mouse_pos.overlaps_rectangle(&self.inspector.zoom_in_button) {
+               self.inspector.zoom_in();
+           } else if mouse_pos.overlaps_rectangle(
+                   &self.inspector.zoom_out_button) {
+               self.inspector.zoom_out();
+           } else if mouse_pos.overlaps_rectangle(&self.inspector.fill_button) {
+               if let Some((id, true, _)) = selected {
+                   self.do_fill(id, payload);
+               }
            }
        } else {
            self.display_menu = None;
        }
 
-       for (i, alloc) in (&self.allocations).iter().enumerate() {
-           let rect = alloc.rect;
-           if mouse_pos.overlaps_rectangle(&rect) {
-               self.display_menu = Some(i);
-               break;
+       let clicked = self.arenas.iter().enumerate()
+           .filter_map(|(i, arena)| arena.block_at(mouse_pos).map(|block| (i, block)))
+           .next();
+       if let Some((i, block)) = clicked {
+           self.switch_arena(i);
+           let arena = &self.arenas[i];
+           if arena.plugin.is_none() {
+               self.display_menu = Some(block.id);
+           } else if block.state == BlockState::Allocated {
+               let ptr = arena.ptr(&block);
+               self.do_plugin_realloc(ptr);
            }
        }
 
        Ok(())
     }
 
+    // Only the buttons that don't need a block apply to pages, and clicking
+    // an allocation frees it.
+    fn handle_page_click(&mut self, mouse_pos: Vector) -> Result<()> {
+        let menu = &self.alloc_menu;
+        if mouse_pos.overlaps_rectangle(&menu.free_ptr_button) {
+            self.do_free_ptr();
+        } else if mouse_pos.overlaps_rectangle(&menu.malloc_button) {
+            self.do_malloc();
+        } else if mouse_pos.overlaps_rectangle(&menu.settings_button) {
+            self.do_settings();
+        } else if let Some(ptr) =
+                self.pages.as_ref().and_then(|grid| grid.ptr_at(mouse_pos)) {
+            self.do_free(ptr);
+        }
+        Ok(())
+    }
+
     fn handle_release(
             &mut self, _event: &Event, _window: &mut Window) -> Result<()> {
-        if !self.sbrk_obj.selected {
+        if self.split_handle.selected {
+            // Splits that aren't allowed were already flagged while
+            // dragging, so they are just dropped here.
+            if let Some((id, point, Ok(()))) = self.split_preview() {
+                let end = self.arenas[self.current].block(id).map_or(point, |b| b.end());
+                MallocState::report(self.heap_mut().split(id, end - point));
+                self.check_heaps();
+            }
+            self.split_block = None;
+            self.sync_split_handle();
             return Ok(());
         }
 
-        let curr_bytes =
-            (self.sbrk_obj.sbrk_rect.x()/(PX_PER_BYTE as f32)) as i32;
-
-        if curr_bytes > (self.sbrk_obj.end_of_heap_bytes + MEM_GAP) {
-            let new_bytes = curr_bytes - self.sbrk_obj.end_of_heap_bytes;
-            self.allocations.push(
-                Block {
-                    rect: Rectangle::new(
-                        (self.sbrk_obj.end_of_heap_bytes, 0),
-                        (new_bytes - MEM_GAP, SBRK_MENU_PX)),
-                    allocated: false,
-                    space_used: 0,
-                });
-            self.sbrk_obj.end_of_heap_bytes = curr_bytes;
-        } else {
-            self.sbrk_obj.sbrk_rect = Rectangle::new(
-                (self.sbrk_obj.end_of_heap_bytes, 0),
-                (SBRK_MENU_PX, SBRK_MENU_PX));
-        }
+        for arena in self.arenas.iter_mut() {
+            if !arena.sbrk_obj.selected {
+                continue;
+            }
 
-        self.sbrk_obj.old_mouse_pos = None;
-        self.sbrk_obj.selected = false;
+            let curr_bytes = arena.sbrk_obj.handle_bytes as i32;
+            let new_bytes = curr_bytes - arena.end();
+            let _ = arena.sbrk(new_bytes);
+            arena.sbrk_obj.release();
+            arena.check();
+        }
         Ok(())
     }
 
     fn handle_mouse_moved(
             &mut self, pos: &Vector, _window: &mut Window) -> Result<()> {
-        if !self.sbrk_obj.selected {
+        if let Some(grid) = &self.pages {
+            self.tooltip.lines = grid.slot_at(*pos)
+                .map_or(vec![], |(page, slot)| grid.heap.describe(page, slot));
+            self.tooltip.pos = *pos;
             return Ok(());
         }
-
-        match self.sbrk_obj.old_mouse_pos {
-            Some(old_mouse_pos) => {
-                let diff = (pos.x - old_mouse_pos.x)/(PX_PER_BYTE as f32);
-                let old_x = self.sbrk_obj.sbrk_rect.x();
-                self.sbrk_obj.sbrk_rect = Rectangle::new((old_x + diff, 0),
-                    (SBRK_MENU_PX, SBRK_MENU_PX));
-
-                self.sbrk_obj.old_mouse_pos = Some(*pos);
+        if self.split_handle.selected {
+            let arena = &self.arenas[self.current];
+            if let Some(block) = self.split_block.and_then(|id| arena.block(id)) {
+                let (start, end) = (block.offset as f32, block.end() as f32);
+                self.split_handle.drag(pos, &arena.view, start, end);
             }
-            _ => {
-                self.sbrk_obj.old_mouse_pos = Some(*pos);
+            return Ok(());
+        }
+
+        let capacity = self.config.heap_capacity as f32;
+        let mut dragging = false;
+        for arena in self.arenas.iter_mut() {
+            if arena.sbrk_obj.selected {
+                arena.sbrk_obj.drag(pos, &arena.view, 0.0, capacity);
+                dragging = true;
             }
         }
+
+        if !dragging {
+            self.hovered = self.arenas.iter().enumerate()
+                .filter_map(|(i, arena)| arena.block_at(*pos).map(|block| (i, block.id)))
+                .next();
+            self.tooltip.pos = *pos;
+        }
         Ok(())
     }
 
-    fn handle_scroll(&mut self, pos: &Vector) -> Result<()> {
-        js! {
-            var box = document.getElementById("render");
-            box.scrollTo(box.scrollLeft + @{pos.x}, box.scrollTop + @{pos.y});
+    // Draws the split handle in the selected block, with the sizes of the
+    // two blocks it would make, or why it can't split there.
+    fn draw_split_handle(&mut self, window: &mut Window) -> Result<()> {
+        self.sync_split_handle();
+        let (id, point, allowed) = match self.split_preview() {
+            Some(preview) => preview,
+            None => return Ok(()),
+        };
+        let (offset, end) = match self.arenas[self.current].block(id) {
+            Some(block) => (block.offset, block.end()),
+            None => return Ok(()),
+        };
+
+        let bar = self.split_handle.bar(point as f32, self.view());
+        let color = if allowed.is_ok() { Color::BLACK } else { Color::RED };
+        window.draw(&bar, Col(color));
+        if !self.split_handle.selected {
+            return Ok(());
         }
 
+        let below = bar.y() + bar.height() + 2.0;
+        let sizes = format!("{} B | {} B", point - offset, end - point);
+        self.text.draw_centered(
+            window,
+            &sizes,
+            Vector::new(bar.center().x, below + STAT_TEXT / 2.0),
+            STAT_TEXT,
+            color)?;
+        if let Err(err) = allowed {
+            self.text.draw(
+                window,
+                &err.to_string(),
+                Vector::new(5.0, below + STAT_TEXT),
+                LABEL_SIZE * 1.5,
+                Color::RED)?;
+        }
         Ok(())
     }
 
-    fn draw_sbrk(&mut self, window: &mut Window) -> Result<()> {
-        window.draw(&self.sbrk_obj.sbrk_rect, Col(Color::CYAN));
-        let text_offset = (SBRK_MENU_PX as f32)/2.0;
-        let text_x = self.sbrk_obj.sbrk_rect.x() + text_offset;
-        let text_y = text_offset;
-        self.sbrk_obj.sbrk.execute(|image| {
-            window.draw(&image.area().with_center((text_x, text_y)), Img(&image));
-            Ok(())
-        })?;
-
-        if self.sbrk_obj.selected {
-            let selected_overlay = Color::BLACK.with_alpha(0.25);
-            window.draw(&self.sbrk_obj.sbrk_rect, Col(selected_overlay));
-
-            let curr_bytes =
-                (self.sbrk_obj.sbrk_rect.x()/(PX_PER_BYTE as f32)) as i32;
-            let new_bytes = curr_bytes - self.sbrk_obj.end_of_heap_bytes;
-
-            if new_bytes >= 0 {
-                draw_num(
-                    &mut self.alloc_menu.font_num_map,
-                    &self.alloc_menu.font_size,
-                    new_bytes,
-                    &Vector::new(
-                        self.sbrk_obj.sbrk_rect.x(),
-                        self.sbrk_obj.sbrk_rect.y()
-                            + self.sbrk_obj.sbrk_rect.height() + 1.0
-                    ),
-                    window
-                )?;
+    // Scrolling over the heap zooms around the cursor, and scrolling anywhere
+    // else pans along the heap.
+    fn handle_scroll(
+            &mut self, pos: &Vector, window: &mut Window) -> Result<()> {
+        let mouse_pos = window.mouse().pos();
+        if self.display_menu.is_some() &&
+                mouse_pos.overlaps_rectangle(&self.inspector.panel) {
+            self.inspector.scroll(if pos.y > 0.0 { 1 } else { -1 });
+            return Ok(());
+        }
+
+        // Arenas are zoomed and panned together so their addresses line up.
+        let over_heap = mouse_pos.y <= self.heap_bottom();
+        for arena in self.arenas.iter_mut() {
+            if pos.x != 0.0 {
+                arena.view.pan(pos.x);
+            } else if over_heap {
+                arena.view.zoom(if pos.y > 0.0 { -1 } else { 1 }, mouse_pos);
+            } else {
+                arena.view.pan(pos.y);
             }
         }
+
+        Ok(())
+    }
+
+    // The page grid and the few menu items that apply to it.
+    fn draw_pages(&mut self, window: &mut Window) -> Result<()> {
+        if let Some(grid) = &self.pages {
+            grid.draw(window, &mut self.text)?;
+        }
+
+        self.alloc_menu.draw_free_ptr_button(window, &mut self.text)?;
+        self.alloc_menu.draw_malloc_button(window, &mut self.text)?;
+        self.alloc_menu.draw_settings_button(window, &mut self.text)?;
+        let unit = self.config.menu_px as f32;
+        let status = self.alloc_menu.malloc_button.pos
+            + Vector::new(2.5 * unit, 5.0);
+        self.text.draw(
+            window, &self.malloc_status, status, STAT_TEXT * 0.75,
+            Color::BLACK)?;
+
+        if !self.tooltip.lines.is_empty() {
+            self.tooltip.draw(window, &mut self.text)?;
+        }
+        if self.show_shortcuts {
+            let bottom = self.heap_bottom();
+            let view = &self.arenas[self.current].view;
+            draw_shortcuts(window, &mut self.text, view, bottom)?;
+        }
         Ok(())
     }
+
+    // Bottom of the arenas or page grid, where the menus start.
+    fn heap_bottom(&self) -> f32 {
+        if self.pages.is_some() {
+            return heap_height(&self.config);
+        }
+        self.arenas.last().map_or(0.0, |arena| arena.view.bottom())
+    }
+}
+
+// Height of whatever shows the heap, above the menu.
+fn heap_height(config: &Config) -> f32 {
+    if config.pages {
+        PageGrid::height(config)
+    } else {
+        stack_height(config)
+    }
 }
 
 impl State for MallocState {
     fn new() -> Result<Self> {
-        let sbrk_asset = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(|font| {
-                let style = FontStyle::new(36.0, Color::BLACK);
-                result(font.render("SBRK", &style))
-            }));
-
-        let sbrk_obj = SbrkDescriptor {
-            end_of_heap_bytes: 0,
-            sbrk: sbrk_asset,
-            sbrk_rect: Rectangle::new((0,0), (SBRK_MENU_PX, SBRK_MENU_PX)),
-            selected: false,
-            old_mouse_pos: None,
-        };
+        let (config, loaded) = MallocState::load_config();
+        if let Err(msg) = loaded {
+            MallocState::alert_user(&msg);
+        }
+        let unit = config.menu_px;
+        let arenas: Vec<Arena> = (0..config.arenas as usize)
+            .map(|i| Arena::new(i, &config))
+            .collect();
+        let menu_y = heap_height(&config) as i32 + unit;
+        let tcaches = (0..MAX_THREADS)
+            .map(|_| Tcache::new(config.tcache_count, config.tcache_max))
+            .collect();
 
-        let alloc_menu = AllocationMenu::new(
-            12.0, 24.0, (SBRK_MENU_PX as f32)*2.0)?;
+        let alloc_menu = AllocationMenu::new(menu_y as f32, unit)?;
+
+        let inspector = Inspector::new(
+            Vector::new(10 * unit, menu_y),
+            Vector::new(9 * unit, config.window_height - 2 * unit - 10));
 
         Ok(MallocState {
-            allocations: vec![],
+            arenas: arenas,
+            current: 0,
+            thread: 0,
+            tcaches: tcaches,
+            pages: if config.pages { Some(PageGrid::new(&config)) } else { None },
+            malloc_status: String::from("malloc hasn't been called yet"),
+            trace: None,
+            comparison: None,
+            config: config,
             alloc_menu: alloc_menu,
-            sbrk_obj: sbrk_obj,
+            inspector: inspector,
+            text: Text::new(),
+            split_handle: DragHandle::new(0.0),
+            split_block: None,
             display_menu: None,
+            diagnostic: None,
+            hovered: None,
+            tooltip: Tooltip::new(),
+            show_shortcuts: false,
+            simulate_corruption: false,
         })
     }
 
+    fn update(&mut self, window: &mut Window) -> Result<()> {
+        let seconds = (window.update_rate() / 1000.0) as f32;
+        for arena in self.arenas.iter_mut() {
+            arena.update(seconds);
+        }
+        Ok(())
+    }
+
     fn event(&mut self, event: &Event, window: &mut Window) -> Result<()> {
 
         match event {
-            Event::MouseButton(button, state) => {
-                if button == &MouseButton::Left {
-                    if state == &ButtonState::Pressed {
-                        return self.handle_click(event, window);
-                    } else if state == &ButtonState::Released {
-                        return self.handle_release(event, window);
-                    }
+            Event::MouseButton(MouseButton::Left, state) => {
+                if state == &ButtonState::Pressed {
+                    return self.handle_click(event, window);
+                } else if state == &ButtonState::Released {
+                    return self.handle_release(event, window);
                 }
             }
             Event::MouseMoved(pos) => {
                 return self.handle_mouse_moved(pos, window);
             }
             Event::MouseWheel(pos) => {
-                return self.handle_scroll(pos);
+                return self.handle_scroll(pos, window);
+            }
+            Event::Key(key, ButtonState::Pressed) => {
+                return self.handle_key(*key, window);
+            }
+            Event::Typed(c) => {
+                return self.handle_typed(*c);
             }
             _=> {}
         }
@@ -377,33 +1049,93 @@ impl State for MallocState {
 
     fn draw(&mut self, window: &mut Window) -> Result<()> {
         window.clear(Color::WHITE)?;
-        self.draw_sbrk(window)?;
-
-        for alloc in (&self.allocations).iter() {
-            let rect = alloc.rect;
-            if alloc.allocated {
-                window.draw(&rect, Col(Color::RED));
-                let filled = Rectangle::new(
-                    (rect.x(), rect.y()),
-                    (alloc.space_used - MEM_GAP, SBRK_MENU_PX));
-                let color = Color::WHITE
-                    .with_red(244.0/256.0)
-                    .with_blue(113.0/256.0)
-                    .with_green(66.0/256.0);
-                window.draw(&filled, Col(color));
-            } else {
-                window.draw(&rect, Col(Color::BLUE));
+        if self.pages.is_some() {
+            return self.draw_pages(window);
+        }
+
+        for (i, arena) in self.arenas.iter().enumerate() {
+            let mut label = Arena::name(i);
+            if let Some(plugin) = &arena.plugin {
+                label = format!("{} ({})", label, plugin.name());
+            }
+            if i == self.current {
+                label = format!("{} <- thread {}", label, self.thread);
             }
+            arena.draw(window, &mut self.text, &self.config, &label)?;
+        }
+        if let Some(comparison) = &self.comparison {
+            comparison.draw_metrics(window, &mut self.text, &self.arenas)?;
+        }
+
+        if self.arenas[0].animator.paused {
+            let paused = "animations paused (p)";
+            let x = window.screen_size().x - Text::width(paused, STAT_TEXT) - 5.0;
+            self.text.draw(
+                window, paused, Vector::new(x, self.heap_bottom() + 2.0),
+                STAT_TEXT, Color::RED)?;
+        }
+
+        self.alloc_menu.draw_free_ptr_button(window, &mut self.text)?;
+        self.alloc_menu.draw_malloc_button(window, &mut self.text)?;
+        let unit = self.config.menu_px as f32;
+        let tcache_area = Rectangle::new(
+            self.alloc_menu.malloc_button.pos + Vector::new(2.5 * unit, 0.0),
+            (4.5 * unit, unit));
+        draw_tcaches(
+            window, &mut self.text, tcache_area, &self.tcaches, self.thread,
+            &self.malloc_status)?;
+        if let Some(bins) = &self.arenas[self.current].heap.bins {
+            let area = Rectangle::new(
+                self.alloc_menu.settings_button.pos + Vector::new(0.0, unit + 5.0),
+                (9.5 * unit, BINS_PANEL_UNITS as f32 * unit - 10.0));
+            let title = format!("bins of {}", Arena::name(self.current));
+            draw_bins(window, &mut self.text, area, &title, bins)?;
         }
+        self.alloc_menu.draw_settings_button(window, &mut self.text)?;
+        self.alloc_menu.draw_simulate_button(
+            window, &mut self.text, self.simulate_corruption)?;
 
-        match self.display_menu {
-            Some(i) => {
-                let block_rect = self.allocations[i].rect;
-                let color = Color::BLACK.with_alpha(0.25);
-                window.draw(&block_rect, Col(color));
-                self.alloc_menu.draw(window, &mut self.allocations[i])?;
+        if let Some(block) = self.selected() {
+            let arena = &self.arenas[self.current];
+            let color = Color::BLACK.with_alpha(0.25);
+            let shown = arena.animator.shown(&block);
+            for rect in shown.payload_rects(&arena.view).iter() {
+                window.draw(rect, Col(color));
             }
-            _ => {}
+            self.alloc_menu.draw(window, &mut self.text, &block)?;
+            self.inspector.draw(window, &mut self.text, &arena.heap, &block)?;
+        }
+        self.draw_split_handle(window)?;
+
+        let arenas = &self.arenas;
+        let hovered = self.hovered.and_then(|(i, id)| {
+            arenas.get(i).and_then(|arena| {
+                arena.block(id).map(|block| {
+                    let mut lines = arena.allocator().describe_block(block.offset);
+                    lines.extend(arena.violations_at(id).into_iter()
+                        .map(|problem| format!("check: {}", problem)));
+                    lines
+                })
+            })
+        });
+        if let Some(lines) = hovered {
+            self.tooltip.lines = lines;
+            self.tooltip.draw(window, &mut self.text)?;
+        }
+
+        let bottom = self.heap_bottom();
+        if let Some(diagnostic) = &mut self.diagnostic {
+            // Point into whichever arena the bad pointer is closest to.
+            let arena = self.arenas.iter()
+                .find(|arena| arena.heap.owns(diagnostic.ptr))
+                .unwrap_or(&self.arenas[self.current]);
+            diagnostic.draw(
+                window, &mut self.text, &arena.view, arena.heap.base, bottom)?;
+        }
+
+        if self.show_shortcuts {
+            let view = &self.arenas[self.current].view;
+            draw_shortcuts(window, &mut self.text, view, bottom)?;
         }
 
         Ok(())
@@ -411,9 +1143,18 @@ impl State for MallocState {
 }
 
 pub fn main() {
+    let (config, _) = MallocState::load_config();
+    // Wrapped heaps and extra arenas are taller than the single strip the
+    // layout assumes, and the bin list needs room under the menu.
+    let mut extra_height = heap_height(&config) as i32;
+    if config.ptmalloc {
+        extra_height += BINS_PANEL_UNITS * config.menu_px;
+    }
     run::<MallocState>(
         "Malloc Visualization",
-        Vector::new(TOTAL_MEMORY*PX_PER_BYTE + SBRK_MENU_PX, 600),
+        Vector::new(
+            config.window_width,
+            config.window_height + extra_height - config.menu_px),
         Settings::default()
     );
 }
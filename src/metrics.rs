@@ -0,0 +1,93 @@
+use crate::allocator::{Allocator, BlockState};
+
+// How well an allocator is doing on a trace, in the terms malloclab grades
+// with.
+#[derive(Clone, Copy, Default)]
+pub struct Metrics {
+    pub ops: usize,
+    pub failures: usize,
+    // Bytes the program has asked for and not freed yet.
+    pub payload: i32,
+    pub peak_payload: i32,
+    pub heap_size: i32,
+    pub peak_heap: i32,
+    // Free bytes in the heap, and the most of them in a single block.
+    pub free: i32,
+    pub largest_free: i32,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // Takes stock after an op, which failed unless `ok`.
+    pub fn record(&mut self, allocator: &dyn Allocator, ok: bool) {
+        self.ops += 1;
+        if !ok {
+            self.failures += 1;
+        }
+
+        let blocks = allocator.describe_blocks();
+        self.payload = blocks.iter()
+            .filter(|b| b.state == BlockState::Allocated)
+            .map(|b| b.used)
+            .sum();
+        let heap_size = allocator.heap_size();
+        let free = blocks.iter()
+            .filter(|b| b.state == BlockState::Free || b.state == BlockState::Top)
+            .filter(|b| b.offset < heap_size);
+        self.free = free.clone().map(|b| b.size).sum();
+        self.largest_free = free.map(|b| b.size).max().unwrap_or(0);
+        self.heap_size = heap_size;
+        self.peak_payload = self.peak_payload.max(self.payload);
+        self.peak_heap = self.peak_heap.max(self.heap_size);
+    }
+
+    // The most payload ever live over the most heap ever used, as in
+    // malloclab's space utilization.
+    pub fn utilization(&self) -> f32 {
+        if self.peak_heap == 0 {
+            return 0.0;
+        }
+        self.peak_payload as f32 / self.peak_heap as f32
+    }
+
+    // How much of the free memory can't be handed out in one piece.
+    pub fn fragmentation(&self) -> f32 {
+        if self.free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f32 / self.free as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bump::Bump;
+    use crate::trace::{Trace, TraceRunner};
+
+    #[test]
+    fn a_small_trace_gives_known_numbers() {
+        let trace = Trace::parse("20000\n2\n4\n1\na 0 10\na 1 20\nf 0\nr 1 40\n").unwrap();
+        let mut bump = Bump::new();
+        bump.init(0, 1 << 12);
+        let mut runner = TraceRunner::new(trace);
+        runner.grow = 64;
+        let mut metrics = Metrics::new();
+        while let Some((_, result)) = runner.step(&mut bump) {
+            metrics.record(&bump, result.is_ok());
+        }
+
+        // The first malloc sbrks 10 + 64 bytes, rounded up to 80. The
+        // realloc grows the last allocation in place, leaving the freed
+        // first one and 24 bytes at the top.
+        assert_eq!((metrics.ops, metrics.failures), (4, 0));
+        assert_eq!((metrics.payload, metrics.peak_payload), (40, 40));
+        assert_eq!((metrics.heap_size, metrics.peak_heap), (80, 80));
+        assert_eq!((metrics.free, metrics.largest_free), (40, 24));
+        assert_eq!(metrics.utilization(), 0.5);
+        assert!((metrics.fragmentation() - 0.4).abs() < 1e-6);
+    }
+}
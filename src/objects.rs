@@ -1,24 +1,22 @@
-use crate::constants::*;
-use crate::draw_num;
+use malloc_vis::allocator::BlockDescription;
+use malloc_vis::bins::Bins;
+use malloc_vis::tcache::Tcache;
+use crate::ruler::hex;
+use crate::text::Text;
+use crate::view::View;
 
 use quicksilver::{
-    Future, Result,
-    combinators::result,
+    Result,
     geom::{Shape, Rectangle, Vector},
-    graphics::{Background::Col, Background::Img, Color, Font, FontStyle, Image},
-    lifecycle::{Asset, Window}
+    graphics::{Background::Col, Color},
+    lifecycle::Window
 };
 
-pub struct Block {
-    pub rect: Rectangle,
-    pub allocated: bool,
-    pub space_used: i32,
-}
-
-pub struct SbrkDescriptor {
-    pub end_of_heap_bytes: i32,
-    pub sbrk: Asset<Image>,
-    pub sbrk_rect: Rectangle,
+// Something the user drags along the heap: the sbrk handle, or the split
+// point inside a block.
+pub struct DragHandle {
+    // Where the handle currently sits, in bytes from the start of the heap.
+    pub handle_bytes: f32,
     pub selected: bool,
     pub old_mouse_pos: Option<Vector>,
 }
@@ -26,180 +24,435 @@ pub struct SbrkDescriptor {
 pub struct AllocationMenu {
     y_offset: f32,
     pub free_button: Rectangle,
-    pub free_text: Asset<Image>,
+
+    pub realloc_button: Rectangle,
+
+    pub free_ptr_button: Rectangle,
+
+    pub malloc_button: Rectangle,
+
+    pub simulate_button: Rectangle,
 
     pub coalesce_left_button: Rectangle,
-    pub coalesce_left_text: Asset<Image>,
     pub coalesce_right_button: Rectangle,
-    pub coalesce_right_text: Asset<Image>,
 
     pub split_button: Rectangle,
-    pub split_text: Asset<Image>,
+
+    pub write_button: Rectangle,
+
+    pub settings_button: Rectangle,
 
     pub allocate_button: Rectangle,
-    pub allocate_text: Asset<Image>,
+}
+
+pub struct Diagnostic {
+    pub ptr: i32,
+    pub lines: Vec<String>,
+}
 
-    pub font_size: Vector,
-    pub font_num_map: Asset<Image>,
+// Describes the block under the mouse.
+pub struct Tooltip {
+    pub pos: Vector,
+    pub lines: Vec<String>,
+}
+
+pub static SHORTCUTS: [(&str, &str); 16] = [
+    ("left/right", "select the previous/next block"),
+    ("up/down", "select the block a row above/below"),
+    ("a", "allocate the selected free block"),
+    ("m", "malloc from the thread's tcache or arena"),
+    ("f", "free the selected block"),
+    ("s", "split the selected block"),
+    ("[ ]", "coalesce the selected block left/right"),
+    ("+ -", "grow/shrink the heap with sbrk"),
+    ("x", "toggle simulate"),
+    ("t", "switch to the next simulated thread"),
+    ("tab", "switch to the next arena"),
+    ("p", "pause/resume animations"),
+    ("n", "run the next op of the loaded trace"),
+    ("e", "export the current arena as an SVG image"),
+    ("esc", "deselect, or close this overlay"),
+    ("?", "show/hide these shortcuts"),
+];
+
+// Size of the text on buttons and in the menu.
+pub static BUTTON_TEXT: f32 = 36.0;
+pub static STAT_TEXT: f32 = 24.0;
+
+impl DragHandle {
+    pub fn new(handle_bytes: f32) -> Self {
+        DragHandle {
+            handle_bytes: handle_bytes,
+            selected: false,
+            old_mouse_pos: None,
+        }
+    }
+
+    // The sbrk handle is a square just past the end of the heap.
+    pub fn rect(&self, view: &View) -> Rectangle {
+        Rectangle::new(
+            view.point(self.handle_bytes),
+            (view.height, view.height))
+    }
+
+    // A split handle is a thin bar straddling the split point.
+    pub fn bar(&self, bytes: f32, view: &View) -> Rectangle {
+        let point = view.point(bytes);
+        Rectangle::new((point.x - 3.0, point.y), (6.0, view.height))
+    }
+
+    // Follows the mouse to `pos`, keeping the handle between `min` and `max`
+    // bytes.
+    pub fn drag(&mut self, pos: &Vector, view: &View, min: f32, max: f32) {
+        // A wrapped heap has no single axis to drag along, so the handle
+        // just follows the cursor.
+        if view.wrapped() {
+            self.handle_bytes = view.bytes_at(*pos);
+        } else if let Some(old_mouse_pos) = self.old_mouse_pos {
+            let diff = (pos.x - old_mouse_pos.x)/view.px_per_byte;
+            self.handle_bytes += diff;
+        }
+        self.handle_bytes = self.handle_bytes.max(min).min(max);
+        self.old_mouse_pos = Some(*pos);
+    }
+
+    pub fn release(&mut self) {
+        self.old_mouse_pos = None;
+        self.selected = false;
+    }
 }
 
 pub fn draw_button(
         button: Rectangle,
-        text: &mut Asset<Image>,
+        label: &str,
+        text: &mut Text,
         window: &mut Window
     ) -> Result<()> {
 
     window.draw(&button, Col(Color::CYAN));
-    text.execute(|image| {
-        window.draw(&image.area().with_center(button.center()), Img(&image));
-        Ok(())
-    })?;
-
-    Ok(())
+    text.draw_centered(
+        window, label, button.center(), BUTTON_TEXT, Color::BLACK)
 }
 
 impl AllocationMenu {
-    pub fn new(font_size_x: f32, font_size_y: f32, y_offset: f32) -> Result<Self> {
-        let font_num_map = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(move |font| {
-                let style = FontStyle::new(font_size_y, Color::BLACK);
-                result(font.render("0123456789", &style))
-            }));
-
-        let free_asset = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(move |font| {
-                let style = FontStyle::new(36.0, Color::BLACK);
-                result(font.render("free", &style))
-            }));
-
-        let allocate_asset = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(move |font| {
-                let style = FontStyle::new(36.0, Color::BLACK);
-                result(font.render("allocate", &style))
-            }));
-
-        let coalesce_right_asset = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(move |font| {
-                let style = FontStyle::new(36.0, Color::BLACK);
-                result(font.render("coalesce-r", &style))
-            }));
-
-        let coalesce_left_asset = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(move |font| {
-                let style = FontStyle::new(36.0, Color::BLACK);
-                result(font.render("coalesce-l", &style))
-            }));
-
-        let split_asset = Asset::new(Font::load("mononoki-Regular.ttf")
-            .and_then(move |font| {
-                let style = FontStyle::new(36.0, Color::BLACK);
-                result(font.render("split", &style))
-            }));
-
-        let center_x = (SBRK_MENU_PX as f32) + 5.0;
-        let center_y = y_offset + (SBRK_MENU_PX as f32)/2.0;
-
-        let button_size = Vector::new(2*SBRK_MENU_PX, SBRK_MENU_PX);
+    pub fn new(y_offset: f32, menu_px: i32) -> Result<Self> {
+        let unit = menu_px as f32;
+        let center_x = unit + 5.0;
+        let center_y = y_offset + unit/2.0;
+
+        let button_size = Vector::new(2*menu_px, menu_px);
 
         Ok(AllocationMenu {
             y_offset: y_offset,
             free_button: Rectangle::new((0, 0), button_size)
                 .with_center((center_x, center_y)),
-            free_text: free_asset,
 
             allocate_button: Rectangle::new((0, 0), button_size)
                 .with_center((center_x, center_y)),
-            allocate_text: allocate_asset,
+
+            realloc_button: Rectangle::new((0, 0), button_size)
+                .with_center((center_x + 2.5 * unit, center_y)),
+
+            free_ptr_button: Rectangle::new((0, 0), button_size)
+                .with_center((center_x + 5.0 * unit, center_y)),
+
+            simulate_button: Rectangle::new((0, 0), button_size)
+                .with_center((center_x + 7.5 * unit, center_y)),
 
             coalesce_left_button: Rectangle::new((0, 0), button_size)
                 .with_center(
                     (
                         center_x,
-                        center_y + 2.0 * (SBRK_MENU_PX as f32)
+                        center_y + 2.0 * unit
                     )),
-            coalesce_left_text: coalesce_left_asset,
             coalesce_right_button: Rectangle::new((0, 0), button_size)
                 .with_center(
                     (
-                        center_x + 2.5 * (SBRK_MENU_PX as f32),
-                        center_y + 2.0 * (SBRK_MENU_PX as f32)
+                        center_x + 2.5 * unit,
+                        center_y + 2.0 * unit
                     )),
-            coalesce_right_text: coalesce_right_asset,
 
             split_button: Rectangle::new((0, 0), button_size)
                 .with_center(
                     (
-                        center_x + 5.0 * (SBRK_MENU_PX as f32),
-                        center_y + 2.0 * (SBRK_MENU_PX as f32)
+                        center_x + 5.0 * unit,
+                        center_y + 2.0 * unit
                     )),
-            split_text: split_asset,
 
-            font_size: Vector::new(font_size_x, font_size_y),
-            font_num_map: font_num_map,
+            write_button: Rectangle::new((0, 0), button_size)
+                .with_center(
+                    (
+                        center_x + 7.5 * unit,
+                        center_y + 2.0 * unit
+                    )),
+
+            settings_button: Rectangle::new((0, 0), button_size)
+                .with_center((center_x, center_y + 3.0 * unit)),
+
+            malloc_button: Rectangle::new((0, 0), button_size)
+                .with_center((center_x + 2.5 * unit, center_y + 3.0 * unit)),
+
         })
     }
 
-    pub fn draw_free_button(&mut self, window: &mut Window) -> Result<()> {
-        draw_button(self.free_button, &mut self.free_text, window)
+    pub fn draw_free_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.free_button, "free", text, window)
+    }
+
+    pub fn draw_allocate_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.allocate_button, "allocate", text, window)
     }
 
-    pub fn draw_allocate_button(&mut self, window: &mut Window) -> Result<()> {
-        draw_button(self.allocate_button, &mut self.allocate_text, window)
+
+    pub fn draw_realloc_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.realloc_button, "realloc", text, window)
     }
 
+    pub fn draw_free_ptr_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.free_ptr_button, "free(ptr)", text, window)
+    }
+
+    pub fn draw_malloc_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.malloc_button, "malloc", text, window)
+    }
 
-    pub fn draw_coalesce_menu(&mut self, window: &mut Window) -> Result<()> {
+    pub fn draw_simulate_button(
+            &mut self,
+            window: &mut Window,
+            text: &mut Text,
+            enabled: bool) -> Result<()> {
+        draw_button(self.simulate_button, "simulate", text, window)?;
+        if enabled {
+            let selected_overlay = Color::BLACK.with_alpha(0.25);
+            window.draw(&self.simulate_button, Col(selected_overlay));
+        }
+        Ok(())
+    }
+
+    pub fn draw_coalesce_menu(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
         draw_button(
             self.coalesce_left_button,
-            &mut self.coalesce_left_text,
+            "coalesce-l",
+            text,
             window
         )?;
 
         draw_button(
             self.coalesce_right_button,
-            &mut self.coalesce_right_text,
+            "coalesce-r",
+            text,
             window
         )
     }
 
-    pub fn draw_split_button(&mut self, window: &mut Window) -> Result<()> {
-        draw_button(self.split_button, &mut self.split_text, window)
+    pub fn draw_split_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.split_button, "split", text, window)
+    }
+
+    pub fn draw_settings_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.settings_button, "settings", text, window)
+    }
+
+    pub fn draw_write_button(
+            &mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        draw_button(self.write_button, "write", text, window)
     }
 
     pub fn draw(
-            &mut self, window: &mut Window, block: &mut Block) -> Result<()> {
+            &mut self,
+            window: &mut Window,
+            text: &mut Text,
+            block: &BlockDescription) -> Result<()> {
         let mut y_off = self.y_offset;
 
-        if block.allocated {
-            self.draw_free_button(window)?;
+        if block.in_use() {
+            self.draw_free_button(window, text)?;
+            self.draw_realloc_button(window, text)?;
+            self.draw_write_button(window, text)?;
         } else {
-            self.draw_allocate_button(window)?;
-            self.draw_coalesce_menu(window)?;
+            self.draw_allocate_button(window, text)?;
+            self.draw_coalesce_menu(window, text)?;
         }
-        self.draw_split_button(window)?;
-
-        y_off += self.free_button.height() + MEM_GAP as f32;
-        let blk_size =
-            (block.rect.width()/(PX_PER_BYTE as f32) + MEM_GAP as f32) as i32;
-        draw_num(
-            &mut self.font_num_map,
-            &self.font_size,
-            blk_size,
-            &Vector::new(MEM_GAP, y_off),
-            window
-        )?;
+        self.draw_split_button(window, text)?;
 
-        let x = self.font_size.x;
-        let x_offset =
-            (blk_size.to_string().chars().count() as f32) * x + 2.0 * x;
-        draw_num(
-            &mut self.font_num_map,
-            &self.font_size,
-            block.space_used,
-            &Vector::new(x_offset, y_off),
-            window
-        )?;
+        y_off += self.free_button.height() + 5.0;
+        let stats = format!(
+            "size {} B  used {} B", block.size, block.used);
+        text.draw(
+            window, &stats, Vector::new(5, y_off), STAT_TEXT, Color::BLACK)
+    }
+}
+
+impl Diagnostic {
+    pub fn new(ptr: i32, text: Vec<String>) -> Self {
+        Diagnostic {
+            ptr: ptr,
+            lines: text,
+        }
+    }
+
+    pub fn draw(
+            &mut self,
+            window: &mut Window,
+            text: &mut Text,
+            view: &View,
+            base: i32,
+            top: f32) -> Result<()> {
+        // Mark where the bad pointer lands relative to the heap.
+        let marker = view.point((self.ptr - base) as f32);
+        window.draw(
+            &Rectangle::new((marker.x - 1.0, marker.y), (3.0, view.height)),
+            Col(Color::MAGENTA));
+
+        let line_height = 30.0;
+        let panel = Rectangle::new(
+            (5.0, top + 5.0),
+            (10.0 * view.height,
+             (self.lines.len() as f32 + 1.0) * line_height));
+        window.draw(&panel, Col(Color::BLACK.with_alpha(0.85)));
+
+        for (i, line) in self.lines.iter().enumerate() {
+            let y = panel.y() + line_height * (i as f32 + 0.5);
+            text.draw(
+                window, line, Vector::new(panel.x() + 10.0, y),
+                24.0, Color::WHITE)?;
+        }
+        Ok(())
+    }
+}
+
+impl Tooltip {
+    pub fn new() -> Self {
+        Tooltip {
+            pos: Vector::new(0, 0),
+            lines: vec![],
+        }
+    }
 
+    pub fn draw(&mut self, window: &mut Window, text: &mut Text) -> Result<()> {
+        let size = 16.0;
+        let line_height = 20.0;
+        let width = self.lines.iter()
+            .map(|line| Text::width(line, size))
+            .fold(0.0, f32::max) + 20.0;
+        let height = self.lines.len() as f32 * line_height + 10.0;
+
+        // Keep the tooltip on screen when hovering near the right edge.
+        let screen = window.screen_size();
+        let x = (self.pos.x + 15.0).min(screen.x - width).max(0.0);
+        let y = (self.pos.y + 15.0).min(screen.y - height).max(0.0);
+        let panel = Rectangle::new((x, y), (width, height));
+        window.draw(&panel, Col(Color::BLACK.with_alpha(0.85)));
+
+        for (i, line) in self.lines.iter().enumerate() {
+            let line_y = y + 5.0 + line_height * i as f32;
+            text.draw(
+                window, line, Vector::new(x + 10.0, line_y),
+                size, Color::WHITE)?;
+        }
         Ok(())
     }
 }
+
+pub fn draw_shortcuts(
+        window: &mut Window,
+        text: &mut Text,
+        view: &View,
+        top: f32) -> Result<()> {
+    let size = 20.0;
+    let line_height = 26.0;
+    let key_width = Text::width("left/right", size) + 20.0;
+    let panel = Rectangle::new(
+        (5.0, top + 5.0),
+        (10.0 * view.height,
+         (SHORTCUTS.len() as f32 + 1.0) * line_height));
+    window.draw(&panel, Col(Color::BLACK.with_alpha(0.85)));
+
+    for (i, (key, action)) in SHORTCUTS.iter().enumerate() {
+        let y = panel.y() + line_height * (i as f32 + 0.5);
+        text.draw(
+            window, key, Vector::new(panel.x() + 10.0, y),
+            size, Color::CYAN)?;
+        text.draw(
+            window, action, Vector::new(panel.x() + 10.0 + key_width, y),
+            size, Color::WHITE)?;
+    }
+    Ok(())
+}
+
+// One line per non-empty bin, head first, e.g.
+// "unsorted: 0x1230 (400 B) <-> 0x1008 (160 B)".
+pub fn draw_bins(
+        window: &mut Window,
+        text: &mut Text,
+        area: Rectangle,
+        title: &str,
+        bins: &Bins) -> Result<()> {
+    let size = 16.0;
+    let line_height = 20.0;
+    window.draw(&area, Col(Color::BLACK.with_alpha(0.05)));
+    let pos = area.pos + Vector::new(5, 5);
+    text.draw(window, title, pos, size, Color::BLACK)?;
+
+    let lists = bins.lists();
+    if lists.is_empty() {
+        let y = pos.y + line_height;
+        return text.draw(
+            window, "all bins are empty", Vector::new(pos.x, y), size,
+            Color::BLACK.with_alpha(0.6));
+    }
+
+    let rows = ((area.height() - 10.0) / line_height) as usize;
+    let max_chars = ((area.width() - 10.0) / Text::width("0", size)) as usize;
+    for (row, (name, bin)) in lists.iter().take(rows.saturating_sub(1)).enumerate() {
+        let chunks: Vec<String> = bin.iter()
+            .map(|c| format!("{} ({} B)", hex(c.ptr), c.size))
+            .collect();
+        let arrow = if name.starts_with("fastbin") { " -> " } else { " <-> " };
+        let mut line = format!("{}: {}", name, chunks.join(arrow));
+        if line.chars().count() > max_chars {
+            line = line.chars().take(max_chars.saturating_sub(3)).collect();
+            line.push_str("...");
+        }
+        let y = pos.y + line_height * (row as f32 + 1.0);
+        text.draw(window, &line, Vector::new(pos.x, y), size, Color::BLACK)?;
+    }
+    Ok(())
+}
+
+// Lists the tcache bins of the current thread and of every other thread
+// that has something cached, under a line saying where the last malloc was
+// served from.
+pub fn draw_tcaches(
+        window: &mut Window,
+        text: &mut Text,
+        area: Rectangle,
+        tcaches: &[Tcache],
+        thread: usize,
+        status: &str) -> Result<()> {
+    let size = 16.0;
+    let line_height = 20.0;
+    window.draw(&area, Col(Color::BLACK.with_alpha(0.05)));
+    let pos = area.pos + Vector::new(5, 5);
+    text.draw(window, status, pos, size, Color::BLACK)?;
+
+    let rows = ((area.height() - 10.0) / line_height) as usize;
+    let shown = tcaches.iter().enumerate()
+        .filter(|(i, tcache)| *i == thread || !tcache.bins.is_empty())
+        .take(rows.saturating_sub(1));
+    for (row, (i, tcache)) in shown.enumerate() {
+        let line = format!("thread {}: {}", i, tcache.describe());
+        let color = if i == thread { Color::RED } else { Color::BLACK };
+        let y = pos.y + line_height * (row as f32 + 1.0);
+        text.draw(window, &line, Vector::new(pos.x, y), size, color)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use malloc_vis::config::Config;
+use malloc_vis::constants::HEAP_BASE;
+use malloc_vis::pages::{PageHeap, PageKind};
+use crate::objects::STAT_TEXT;
+use crate::text::Text;
+
+use quicksilver::{
+    Result,
+    geom::{Rectangle, Vector},
+    graphics::{Background::Col, Color},
+    lifecycle::Window
+};
+
+static PAGE_COLUMNS: usize = 8;
+static PAGE_GAP: f32 = 20.0;
+static LABEL_PX: f32 = 24.0;
+// Most slots drawn side by side within a page.
+static SLOT_COLUMNS: usize = 16;
+
+// Draws a PageHeap as a grid of pages, each showing its slots with the ones
+// in use filled in, rather than as one long strip of bytes.
+pub struct PageGrid {
+    pub heap: PageHeap,
+    cell: Vector,
+}
+
+impl PageGrid {
+    pub fn new(config: &Config) -> Self {
+        let width = config.window_width as f32 / PAGE_COLUMNS as f32 - PAGE_GAP;
+        PageGrid {
+            heap: PageHeap::new(
+                HEAP_BASE,
+                config.page_size.min(config.heap_capacity),
+                config.heap_capacity),
+            cell: Vector::new(width, width * 0.6),
+        }
+    }
+
+    // Height of the whole grid.
+    pub fn height(config: &Config) -> f32 {
+        let grid = PageGrid::new(config);
+        let rows = grid.heap.pages.len().div_ceil(PAGE_COLUMNS);
+        rows as f32 * (grid.cell.y + PAGE_GAP)
+    }
+
+    fn page_rect(&self, page: usize) -> Rectangle {
+        let column = (page % PAGE_COLUMNS) as f32;
+        let row = (page / PAGE_COLUMNS) as f32;
+        Rectangle::new(
+            (PAGE_GAP / 2.0 + column * (self.cell.x + PAGE_GAP),
+             row * (self.cell.y + PAGE_GAP)),
+            self.cell)
+    }
+
+    // The part of a page below its label, where its slots are drawn.
+    fn body(&self, page: usize) -> Rectangle {
+        let rect = self.page_rect(page);
+        Rectangle::new(
+            (rect.x(), rect.y() + LABEL_PX),
+            (rect.width(), rect.height() - LABEL_PX))
+    }
+
+    fn slot_rects(&self, page: usize, slots: usize) -> Vec<Rectangle> {
+        let body = self.body(page);
+        let columns = slots.min(SLOT_COLUMNS).max(1);
+        let rows = slots.div_ceil(columns);
+        let size = Vector::new(
+            body.width() / columns as f32, body.height() / rows as f32);
+        (0..slots).map(|slot| {
+            Rectangle::new(
+                (body.x() + (slot % columns) as f32 * size.x,
+                 body.y() + (slot / columns) as f32 * size.y),
+                size)
+        }).collect()
+    }
+
+    // The page and slot under `pos`. Pages that aren't split into slots
+    // count as a single slot.
+    pub fn slot_at(&self, pos: Vector) -> Option<(usize, usize)> {
+        let page = (0..self.heap.pages.len())
+            .find(|page| pos.overlaps_rectangle(&self.page_rect(*page)))?;
+        let slot = match &self.heap.pages[page] {
+            PageKind::Small { used, .. } => self.slot_rects(page, used.len())
+                .iter()
+                .position(|rect| pos.overlaps_rectangle(rect))
+                .unwrap_or(0),
+            _ => 0,
+        };
+        Some((page, slot))
+    }
+
+    // The pointer to the allocation under `pos`, if there is one.
+    pub fn ptr_at(&self, pos: Vector) -> Option<i32> {
+        let (page, slot) = self.slot_at(pos)?;
+        match &self.heap.pages[page] {
+            PageKind::Small { used, .. } if used[slot].is_some() =>
+                Some(self.heap.slot_addr(page, slot)),
+            PageKind::Large { .. } => Some(self.heap.page_addr(page)),
+            _ => None,
+        }
+    }
+
+    pub fn draw(&self, window: &mut Window, text: &mut Text) -> Result<()> {
+        let used_color = Color::WHITE
+            .with_red(244.0/256.0)
+            .with_blue(113.0/256.0)
+            .with_green(66.0/256.0);
+        for (page, kind) in self.heap.pages.iter().enumerate() {
+            let rect = self.page_rect(page);
+            let body = self.body(page);
+            window.draw(&rect, Col(Color::BLACK.with_alpha(0.05)));
+
+            let label = match kind {
+                PageKind::Unused => format!("#{} unused", page),
+                PageKind::Small { class, used, .. } => format!(
+                    "#{} {} B {}/{}", page, class,
+                    used.iter().filter(|s| s.is_some()).count(), used.len()),
+                PageKind::Large { pages, .. } =>
+                    format!("#{} large x{}", page, pages),
+                PageKind::Continuation => format!("#{} (large)", page),
+            };
+            text.draw(window, &label, rect.pos, STAT_TEXT * 0.75, Color::BLACK)?;
+
+            match kind {
+                PageKind::Unused => {}
+                PageKind::Small { class, used, free } => {
+                    let rects = self.slot_rects(page, used.len());
+                    let head = free.last();
+                    for (slot, rect) in rects.iter().enumerate() {
+                        let inner = Rectangle::new(
+                            rect.pos + Vector::new(1, 1),
+                            rect.size - Vector::new(2, 2));
+                        match used[slot] {
+                            Some(bytes) => {
+                                window.draw(&inner, Col(Color::RED));
+                                let filled = Rectangle::new(
+                                    inner.pos,
+                                    (inner.width() * bytes as f32
+                                        / *class as f32, inner.height()));
+                                window.draw(&filled, Col(used_color));
+                            }
+                            None if head == Some(&slot) =>
+                                window.draw(&inner, Col(Color::CYAN)),
+                            None => window.draw(&inner, Col(Color::BLUE)),
+                        }
+                    }
+                }
+                PageKind::Large { used, .. } => {
+                    window.draw(&body, Col(Color::RED));
+                    let fraction =
+                        (*used as f32 / self.heap.page_size as f32).min(1.0);
+                    let filled = Rectangle::new(
+                        body.pos, (body.width() * fraction, body.height()));
+                    window.draw(&filled, Col(used_color));
+                }
+                PageKind::Continuation => {
+                    window.draw(&body, Col(Color::RED.with_alpha(0.6)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,372 @@
+use crate::heap::HeapError;
+
+// Slot sizes small allocations are rounded up to, roughly mimalloc's bins.
+// Anything bigger than a quarter of a page gets a run of whole pages.
+pub static SIZE_CLASSES: [i32; 20] = [
+    8, 16, 32, 48, 64, 80, 96, 112, 128, 160,
+    192, 224, 256, 320, 384, 448, 512, 640, 768, 1024,
+];
+
+pub enum PageKind {
+    Unused,
+    // Carved into equal slots of one size class. `free` is the page's own
+    // free list, head last, and `used` the bytes asked for in each slot,
+    // or None while the slot is free, which doubles as the free bitmap.
+    Small {
+        class: i32,
+        used: Vec<Option<i32>>,
+        free: Vec<usize>,
+    },
+    // The first page of a run of `pages` pages holding one big allocation.
+    Large {
+        pages: usize,
+        used: i32,
+    },
+    // Any later page of a large run.
+    Continuation,
+}
+
+// A heap made of fixed-size pages, each dedicated to a single size class as
+// in jemalloc's runs and mimalloc's pages. There are no headers and nothing
+// to coalesce: a slot's size comes from the page it is in, and a page goes
+// back to being unused once all of its slots are free.
+pub struct PageHeap {
+    pub base: i32,
+    pub page_size: i32,
+    pub pages: Vec<PageKind>,
+    // Pointers that were handed out and then freed, to tell double frees
+    // from pointers that were never valid.
+    freed: Vec<i32>,
+}
+
+impl PageHeap {
+    pub fn new(base: i32, page_size: i32, capacity: i32) -> Self {
+        PageHeap {
+            base: base,
+            page_size: page_size,
+            pages: (0..capacity / page_size).map(|_| PageKind::Unused).collect(),
+            freed: vec![],
+        }
+    }
+
+    pub fn class_of(&self, bytes: i32) -> Option<i32> {
+        SIZE_CLASSES.iter().cloned()
+            .filter(|class| *class <= self.page_size / 4)
+            .find(|class| *class >= bytes.max(1))
+    }
+
+    pub fn page_addr(&self, page: usize) -> i32 {
+        self.base + page as i32 * self.page_size
+    }
+
+    // The pointer handed out for `slot` in `page`.
+    pub fn slot_addr(&self, page: usize, slot: usize) -> i32 {
+        match &self.pages[page] {
+            PageKind::Small { class, .. } =>
+                self.page_addr(page) + slot as i32 * class,
+            _ => self.page_addr(page),
+        }
+    }
+
+    // Returns the pointer and the page it came from.
+    pub fn malloc(&mut self, bytes: i32) -> Result<(i32, usize), HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        match self.class_of(bytes) {
+            Some(class) => self.malloc_small(class, bytes),
+            None => self.malloc_large(bytes),
+        }
+    }
+
+    fn malloc_small(
+            &mut self, class: i32, bytes: i32) -> Result<(i32, usize), HeapError> {
+        let has_room = |kind: &PageKind| match kind {
+            PageKind::Small { class: c, free, .. } =>
+                *c == class && !free.is_empty(),
+            _ => false,
+        };
+        let page = match self.pages.iter().position(has_room) {
+            Some(page) => page,
+            None => {
+                let page = self.pages.iter()
+                    .position(|kind| matches!(kind, PageKind::Unused))
+                    .ok_or(HeapError::OutOfMemory(bytes))?;
+                let slots = (self.page_size / class) as usize;
+                self.pages[page] = PageKind::Small {
+                    class: class,
+                    used: vec![None; slots],
+                    free: (0..slots).rev().collect(),
+                };
+                page
+            }
+        };
+
+        let slot = match &mut self.pages[page] {
+            PageKind::Small { used, free, .. } => {
+                let slot = free.pop().ok_or(HeapError::OutOfMemory(bytes))?;
+                used[slot] = Some(bytes);
+                slot
+            }
+            _ => return Err(HeapError::OutOfMemory(bytes)),
+        };
+        let ptr = self.slot_addr(page, slot);
+        self.freed.retain(|p| *p != ptr);
+        Ok((ptr, page))
+    }
+
+    fn malloc_large(&mut self, bytes: i32) -> Result<(i32, usize), HeapError> {
+        let rounded = bytes.checked_add(self.page_size - 1)
+            .ok_or(HeapError::OutOfMemory(bytes))?;
+        let needed = (rounded / self.page_size) as usize;
+        let start = (0..self.pages.len())
+            .find(|start| {
+                start + needed <= self.pages.len() &&
+                    self.pages[*start..start + needed].iter()
+                        .all(|kind| matches!(kind, PageKind::Unused))
+            })
+            .ok_or(HeapError::OutOfMemory(bytes))?;
+
+        self.pages[start] = PageKind::Large { pages: needed, used: bytes };
+        for page in start + 1..start + needed {
+            self.pages[page] = PageKind::Continuation;
+        }
+        let ptr = self.page_addr(start);
+        self.freed.retain(|p| *p != ptr);
+        Ok((ptr, start))
+    }
+
+    // The first page of the large run that `page` belongs to.
+    fn run_start(&self, page: usize) -> usize {
+        (0..=page).rev()
+            .find(|p| !matches!(self.pages[*p], PageKind::Continuation))
+            .unwrap_or(page)
+    }
+
+    // Why `ptr` can't be freed, given it doesn't point at a slot in use.
+    fn bad_free(&self, ptr: i32) -> HeapError {
+        if self.freed.contains(&ptr) {
+            HeapError::DoubleFree(ptr)
+        } else {
+            HeapError::InvalidPointer(ptr)
+        }
+    }
+
+    pub fn free(&mut self, ptr: i32) -> Result<usize, HeapError> {
+        let offset = ptr - self.base;
+        if offset < 0 || offset >= self.pages.len() as i32 * self.page_size {
+            return Err(HeapError::OutOfHeap(ptr));
+        }
+        let page = (offset / self.page_size) as usize;
+        let within = offset % self.page_size;
+
+        let start = self.run_start(page);
+        let empty = match &mut self.pages[page] {
+            PageKind::Unused => return Err(self.bad_free(ptr)),
+            PageKind::Continuation => {
+                let start = self.page_addr(start);
+                return Err(HeapError::InteriorPointer(ptr, start));
+            }
+            PageKind::Large { .. } if within != 0 => {
+                let start = self.page_addr(page);
+                return Err(HeapError::InteriorPointer(ptr, start));
+            }
+            PageKind::Large { pages, .. } => {
+                let pages = *pages;
+                for kind in self.pages[page..page + pages].iter_mut() {
+                    *kind = PageKind::Unused;
+                }
+                false
+            }
+            PageKind::Small { class, used, free } => {
+                let slot = (within / *class) as usize;
+                if slot >= used.len() {
+                    return Err(HeapError::InvalidPointer(ptr));
+                }
+                if within % *class != 0 {
+                    let start = ptr - within % *class;
+                    return Err(HeapError::InteriorPointer(ptr, start));
+                }
+                if used[slot].is_none() {
+                    return Err(if self.freed.contains(&ptr) {
+                        HeapError::DoubleFree(ptr)
+                    } else {
+                        HeapError::InvalidPointer(ptr)
+                    });
+                }
+                used[slot] = None;
+                free.push(slot);
+                used.iter().all(|slot| slot.is_none())
+            }
+        };
+
+        // mimalloc retires a page as soon as it is empty, so it can be
+        // reused for any size class.
+        if empty {
+            self.pages[page] = PageKind::Unused;
+        }
+        self.freed.push(ptr);
+        Ok(page)
+    }
+
+    // Keeps the allocation where it is if its slot or run is still the
+    // right size for `bytes`, and moves it otherwise.
+    pub fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        let offset = ptr - self.base;
+        let page = (offset / self.page_size) as usize;
+        let class = self.class_of(bytes);
+        let page_size = self.page_size;
+        let stays = match self.pages.get_mut(page) {
+            Some(PageKind::Small { class: c, used, .. }) if offset >= 0 => {
+                // As in free, a pointer between slots was never handed out.
+                if offset % page_size % *c != 0 {
+                    return Err(HeapError::InvalidPointer(ptr));
+                }
+                let slot = ((offset % page_size) / *c) as usize;
+                match used.get_mut(slot) {
+                    Some(Some(old)) if Some(*c) == class => {
+                        *old = bytes;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Some(PageKind::Large { pages, used })
+                    if offset >= 0 && offset % page_size == 0 &&
+                        class.is_none() && bytes <= *pages as i32 * page_size => {
+                *used = bytes;
+                true
+            }
+            _ => false,
+        };
+        if stays {
+            return Ok(ptr);
+        }
+
+        // A bad pointer is only found out by freeing it, so the new
+        // allocation is undone when that fails.
+        let (new_ptr, _) = self.malloc(bytes)?;
+        if let Err(err) = self.free(ptr) {
+            self.free(new_ptr)?;
+            return Err(err);
+        }
+        Ok(new_ptr)
+    }
+
+    // One line per fact about a slot, or a large run's page, for tooltips.
+    pub fn describe(&self, page: usize, slot: usize) -> Vec<String> {
+        let addr = self.page_addr(page);
+        let mut lines = vec![format!("page {} at 0x{:x}", page, addr)];
+        match &self.pages[page] {
+            PageKind::Unused => lines.push(String::from("unused")),
+            PageKind::Small { class, used, free } => {
+                let in_use = used.iter().filter(|s| s.is_some()).count();
+                lines.push(format!("{} B slots, {} of {} in use",
+                    class, in_use, used.len()));
+                let ptr = self.slot_addr(page, slot);
+                lines.push(match used.get(slot) {
+                    Some(Some(bytes)) => format!(
+                        "slot {} at 0x{:x}: {} B used", slot, ptr, bytes),
+                    _ => format!("slot {} at 0x{:x}: free", slot, ptr),
+                });
+                let next: Vec<String> = free.iter().rev().take(4)
+                    .map(|s| s.to_string())
+                    .collect();
+                lines.push(format!("free list: {}{}", next.join(" -> "),
+                    if free.len() > 4 { " -> ..." } else { "" }));
+            }
+            PageKind::Large { pages, used } => {
+                lines.push(format!("large run of {} pages, {} B used",
+                    pages, used));
+            }
+            PageKind::Continuation => {
+                let start = self.run_start(page);
+                lines.push(format!("part of the large run at 0x{:x}",
+                    self.page_addr(start)));
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heap() -> PageHeap {
+        PageHeap::new(0x1000, 4096, 4 * 4096)
+    }
+
+    #[test]
+    fn small_requests_share_a_page() {
+        let mut heap = heap();
+        let (a, page_a) = heap.malloc(20).unwrap();
+        let (b, page_b) = heap.malloc(30).unwrap();
+        assert_eq!((a, b), (0x1000, 0x1020));
+        assert_eq!(page_a, page_b);
+        let (c, page_c) = heap.malloc(40).unwrap();
+        assert_eq!((c, page_c), (0x2000, 1));
+    }
+
+    #[test]
+    fn empty_pages_are_retired() {
+        let mut heap = heap();
+        let (ptr, page) = heap.malloc(20).unwrap();
+        heap.free(ptr).unwrap();
+        assert!(matches!(heap.pages[page], PageKind::Unused));
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut heap = heap();
+        let (ptr, _) = heap.malloc(20).unwrap();
+        heap.malloc(20).unwrap();
+        heap.free(ptr).unwrap();
+        assert!(matches!(heap.free(ptr), Err(HeapError::DoubleFree(p)) if p == ptr));
+    }
+
+    #[test]
+    fn interior_pointers_are_rejected() {
+        let mut heap = heap();
+        let (ptr, _) = heap.malloc(20).unwrap();
+        assert!(matches!(
+            heap.free(ptr + 4), Err(HeapError::InteriorPointer(p, start))
+                if p == ptr + 4 && start == ptr));
+        assert!(matches!(
+            heap.realloc(ptr + 4, 24), Err(HeapError::InvalidPointer(p)) if p == ptr + 4));
+    }
+
+    #[test]
+    fn large_runs_take_whole_pages() {
+        let mut heap = heap();
+        let (ptr, page) = heap.malloc(5000).unwrap();
+        assert_eq!((ptr, page), (0x1000, 0));
+        assert!(matches!(heap.pages[0], PageKind::Large { pages: 2, used: 5000 }));
+        assert!(matches!(heap.pages[1], PageKind::Continuation));
+        assert!(matches!(
+            heap.free(0x2000), Err(HeapError::InteriorPointer(_, 0x1000))));
+        heap.free(ptr).unwrap();
+        assert!(heap.pages.iter().all(|kind| matches!(kind, PageKind::Unused)));
+    }
+
+    #[test]
+    fn huge_requests_fail_instead_of_overflowing() {
+        let mut heap = heap();
+        assert!(matches!(heap.malloc(i32::MAX), Err(HeapError::OutOfMemory(_))));
+        let (ptr, _) = heap.malloc(20).unwrap();
+        assert!(matches!(heap.realloc(ptr, i32::MAX), Err(HeapError::OutOfMemory(_))));
+    }
+
+    #[test]
+    fn realloc_stays_within_the_size_class() {
+        let mut heap = heap();
+        let (ptr, _) = heap.malloc(20).unwrap();
+        assert!(matches!(heap.realloc(ptr, 30), Ok(p) if p == ptr));
+        let moved = heap.realloc(ptr, 100).unwrap();
+        assert_ne!(moved, ptr);
+        assert!(matches!(heap.free(ptr), Err(HeapError::DoubleFree(_))));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+
+use proptest::prelude::*;
+
+use crate::bins::Bins;
+use crate::checker::check_heap;
+use crate::heap::Heap;
+use crate::trace;
+use crate::workload::{Workload, PATTERNS};
+
+// What the buttons and keys do to a heap. Blocks are picked by position,
+// wrapping around, so any generated index lands on one.
+#[derive(Clone, Debug)]
+enum Op {
+    Sbrk(i32),
+    Allocate(usize, i32),
+    Malloc(i32),
+    Free(usize),
+    Split(usize, i32),
+    Coalesce(usize, bool),
+}
+
+fn sbrk() -> impl Strategy<Value = Op> {
+    (-128..512i32).prop_map(Op::Sbrk)
+}
+
+fn by_hand() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        sbrk(),
+        (any::<usize>(), 0..256i32).prop_map(|(i, bytes)| Op::Allocate(i, bytes)),
+        any::<usize>().prop_map(Op::Free),
+        (any::<usize>(), 0..256i32).prop_map(|(i, bytes)| Op::Split(i, bytes)),
+        (any::<usize>(), any::<bool>()).prop_map(|(i, left)| Op::Coalesce(i, left)),
+    ]
+}
+
+// ptmalloc keeps free chunks merged itself, so only malloc and free go
+// through it.
+fn by_malloc() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        sbrk(),
+        (0..1536i32).prop_map(Op::Malloc),
+        any::<usize>().prop_map(Op::Free),
+    ]
+}
+
+// Runs `op`, returning how far it moved the break. Errors are fine, since
+// plenty of generated ops aren't allowed; they just mustn't break anything.
+fn apply(heap: &mut Heap, op: &Op) -> i32 {
+    let before = heap.end_of_heap_bytes;
+    let pick = |heap: &Heap, i: usize| {
+        heap.blocks.get(i % heap.blocks.len().max(1)).map(|b| (b.id, b.addr()))
+    };
+    match *op {
+        Op::Sbrk(bytes) if bytes >= 0 => {
+            if heap.sbrk(bytes).is_ok() {
+                return bytes - bytes % heap.alignment;
+            }
+        }
+        Op::Sbrk(bytes) => {
+            if heap.shrink(-bytes).is_ok() {
+                return -(-bytes - -bytes % heap.alignment);
+            }
+        }
+        Op::Allocate(i, bytes) => {
+            if let Some((id, _)) = pick(heap, i) {
+                let _ = heap.allocate(id, bytes);
+            }
+        }
+        Op::Malloc(bytes) => {
+            let _ = heap.malloc(bytes);
+        }
+        Op::Free(i) => {
+            // Only live blocks, so double frees don't show up as failures.
+            let live: Vec<i32> = heap.blocks.iter()
+                .filter(|b| b.allocated && b.cached.is_none())
+                .map(|b| b.addr())
+                .collect();
+            if !live.is_empty() {
+                let _ = heap.free(live[i % live.len()]);
+            }
+        }
+        Op::Split(i, bytes) => {
+            if let Some((id, _)) = pick(heap, i) {
+                let _ = heap.split(id, bytes);
+            }
+        }
+        Op::Coalesce(i, left) => {
+            if let Some((id, _)) = pick(heap, i) {
+                let _ = if left {
+                    heap.coalesce_left(id)
+                } else {
+                    heap.coalesce_right(id)
+                };
+            }
+        }
+    }
+    heap.end_of_heap_bytes - before
+}
+
+// Blocks tile the heap with no gaps or overlaps, and add up to exactly the
+// bytes sbrk handed out, on top of everything the heap checker looks at.
+fn check(heap: &Heap, sbrked: i32) -> Result<(), TestCaseError> {
+    let mut end = 0;
+    for block in heap.blocks.iter() {
+        prop_assert_eq!(block.offset, end, "gap or overlap at block {}", block.id);
+        prop_assert!(block.size > block.header, "block {} has no payload", block.id);
+        end = block.end();
+    }
+    prop_assert_eq!(end, heap.end_of_heap_bytes);
+    prop_assert_eq!(heap.end_of_heap_bytes, sbrked);
+
+    let problems: Vec<String> = check_heap(heap).into_iter()
+        .map(|v| format!("{:?}: {}", v.block, v.message))
+        .collect();
+    prop_assert!(problems.is_empty(), "{:?}", problems);
+    Ok(())
+}
+
+fn run(mut heap: Heap, ops: &[Op]) -> Result<(), TestCaseError> {
+    let mut sbrked = 0;
+    for op in ops.iter() {
+        let moved = apply(&mut heap, op);
+        if let Op::Sbrk(_) = op {
+            sbrked += moved;
+        }
+        check(&heap, sbrked)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn ops_by_hand_keep_the_heap_consistent(
+            header in 1..24i32,
+            alignment in prop::sample::select(vec![1, 4, 8, 16]),
+            ops in prop::collection::vec(by_hand(), 1..64)) {
+        run(Heap::with_geometry(4096, header, alignment, 4096), &ops)?;
+    }
+
+    #[test]
+    fn ptmalloc_keeps_the_heap_consistent(
+            ops in prop::collection::vec(by_malloc(), 1..96)) {
+        let mut heap = Heap::with_geometry(4096, 16, 16, 1 << 14);
+        heap.bins = Some(Bins::new());
+        run(heap, &ops)?;
+    }
+
+    // Every id is malloced once, only used while live, and freed by the end.
+    #[test]
+    fn workloads_free_what_they_allocate(
+            pattern in prop::sample::select(PATTERNS.to_vec()),
+            count in 0..200usize,
+            seed in any::<u64>()) {
+        let spec = format!("{},power:1-4096,n={},seed={}", pattern, count, seed);
+        let mut live = BTreeSet::new();
+        for op in Workload::parse(&spec).unwrap().generate().ops {
+            match op {
+                trace::Op::Malloc { id, .. } => prop_assert!(live.insert(id)),
+                trace::Op::Realloc { id, .. } => prop_assert!(live.contains(&id)),
+                trace::Op::Free { id } => prop_assert!(live.remove(&id)),
+            }
+        }
+        prop_assert!(live.is_empty());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::text::Text;
+use crate::view::{View, RULER_PX};
+
+use quicksilver::{
+    Result,
+    geom::{Rectangle, Vector},
+    graphics::{Background::Col, Color},
+    lifecycle::Window
+};
+
+// Labelled ticks are never drawn closer together than this.
+static MIN_TICK_PX: f32 = 80.0;
+static MINOR_TICKS: i32 = 4;
+pub static LABEL_SIZE: f32 = 12.0;
+
+pub fn hex(addr: i32) -> String {
+    format!("0x{:x}", addr)
+}
+
+// Bytes between labelled ticks: the smallest power of two that keeps them
+// at least MIN_TICK_PX apart at the current zoom.
+fn tick_step(view: &View) -> i32 {
+    let mut step = 1;
+    while view.width(step as f32) < MIN_TICK_PX {
+        step *= 2;
+    }
+    step
+}
+
+// Tick marks and addresses above the heap. A single strip is labelled with
+// absolute addresses, while a wrapped heap is labelled with offsets into a
+// row since the gutter already gives each row's start address.
+pub fn draw_ruler(
+        window: &mut Window,
+        text: &mut Text,
+        view: &View,
+        base: i32) -> Result<()> {
+    let (first, last) = view.visible_bytes(window.screen_size().x);
+    let step = tick_step(view);
+    let minor = step / MINOR_TICKS;
+    let tick_step = if minor > 0 && view.width(minor as f32) >= 8.0 {
+        minor
+    } else {
+        step
+    };
+
+    let origin = view.point(first).x - view.width(first);
+    let mut bytes = (first as i32 / tick_step) * tick_step;
+    while bytes as f32 <= last {
+        let x = origin + view.width(bytes as f32);
+        let major = bytes % step == 0;
+        let height = if major { 10.0 } else { 5.0 };
+        window.draw(
+            &Rectangle::new(
+                (x, view.top + RULER_PX - height), (1.0, height)),
+            Col(Color::BLACK));
+
+        if major {
+            let (top, bottom) = if view.wrapped() {
+                (format!("+{}", hex(bytes)), format!("+{}", bytes))
+            } else {
+                (hex(base + bytes), (base + bytes).to_string())
+            };
+            text.draw(
+                window, &top, Vector::new(x + 2.0, view.top),
+                LABEL_SIZE, Color::BLACK)?;
+            text.draw(
+                window, &bottom, Vector::new(x + 2.0, view.top + LABEL_SIZE),
+                LABEL_SIZE, Color::BLACK.with_alpha(0.6))?;
+        }
+        bytes += tick_step;
+    }
+    Ok(())
+}
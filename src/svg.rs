@@ -0,0 +1,322 @@
+use std::fmt::Write;
+
+use crate::allocator::{Allocator, BlockDescription, BlockState};
+
+// Sizes in the exported picture, in pixels.
+static MARGIN: f32 = 20.0;
+static ROW_HEIGHT: f32 = 60.0;
+// Room above each row for the free list arrows.
+static ARROW_SPACE: f32 = 50.0;
+static FONT: f32 = 11.0;
+static LINE: f32 = 16.0;
+
+// The same colors the canvas uses.
+static ALLOCATED: &str = "#ff0000";
+static USED: &str = "#f44271";
+static FREE: &str = "#0000ff";
+static TCACHE: &str = "#00ff00";
+static FASTBIN: &str = "#008000";
+
+// Free lists are told apart by the color of their arrows.
+static LIST_COLORS: [&str; 6] = [
+    "#000000", "#8000c0", "#008080", "#c06000", "#0060c0", "#a00040",
+];
+
+fn hex(addr: i32) -> String {
+    format!("0x{:x}", addr)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// Roughly how wide `text` is in the monospace font.
+fn text_width(text: &str) -> f32 {
+    text.chars().count() as f32 * FONT * 0.6
+}
+
+// A heap at one moment, with what should be written next to it.
+pub struct Frame {
+    pub title: String,
+    pub base: i32,
+    pub blocks: Vec<BlockDescription>,
+    pub free_lists: Vec<(String, Vec<i32>)>,
+    // Lines of text under the heap.
+    pub stats: Vec<String>,
+}
+
+impl Frame {
+    pub fn of(allocator: &dyn Allocator, base: i32, title: &str) -> Self {
+        let blocks = allocator.describe_blocks();
+        let end = blocks.iter().map(|b| b.end()).max().unwrap_or(0);
+        let allocated: Vec<&BlockDescription> = blocks.iter()
+            .filter(|b| b.state == BlockState::Allocated)
+            .collect();
+        let free: Vec<i32> = blocks.iter()
+            .filter(|b| b.state == BlockState::Free || b.state == BlockState::Top)
+            .map(|b| b.size)
+            .collect();
+        let stats = vec![
+            format!("heap {} to {}, {} B", hex(base), hex(base + end), end),
+            format!("{} B in use in {} blocks",
+                allocated.iter().map(|b| b.used).sum::<i32>(), allocated.len()),
+            format!("{} B free in {} blocks, the largest {} B",
+                free.iter().sum::<i32>(), free.len(),
+                free.iter().max().cloned().unwrap_or(0)),
+        ];
+        Frame {
+            title: String::from(title),
+            base: base,
+            free_lists: allocator.free_lists(),
+            blocks: blocks,
+            stats: stats,
+        }
+    }
+
+    // Draws the frame `width` pixels wide, wrapped into rows of `row_bytes`
+    // like the canvas, or in one strip when that's 0.
+    pub fn render(&self, width: f32, row_bytes: i32) -> String {
+        let end = self.blocks.iter().map(|b| b.end()).max().unwrap_or(0).max(1);
+        let row_bytes = if row_bytes > 0 { row_bytes } else { end };
+        let layout = Layout {
+            px_per_byte: (width - 2.0 * MARGIN) / row_bytes as f32,
+            row_bytes: row_bytes,
+        };
+        let rows = (end + row_bytes - 1) / row_bytes;
+        let stats_top = layout.row_top(rows) + LINE;
+        let height = stats_top + LINE * self.stats.len() as f32 + MARGIN;
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            concat!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\"",
+                " height=\"{}\" viewBox=\"0 0 {} {}\"",
+                " font-family=\"monospace\" font-size=\"{}\">"),
+            width, height, width, height, FONT);
+        let _ = writeln!(svg, "<defs>");
+        for (i, color) in LIST_COLORS.iter().enumerate() {
+            let _ = writeln!(
+                svg,
+                concat!("<marker id=\"arrow{}\" viewBox=\"0 0 8 8\" refX=\"8\"",
+                    " refY=\"4\" markerWidth=\"8\" markerHeight=\"8\"",
+                    " orient=\"auto\"><path d=\"M0,0 L8,4 L0,8 z\" fill=\"{}\"/>",
+                    "</marker>"),
+                i, color);
+        }
+        let _ = writeln!(svg, "</defs>");
+        let _ = writeln!(svg, "<rect width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>");
+        let _ = writeln!(
+            svg, "<text x=\"{}\" y=\"{}\" font-size=\"{}\">{}</text>",
+            MARGIN, MARGIN, FONT * 1.5, escape(&self.title));
+
+        for block in self.blocks.iter() {
+            self.render_block(&mut svg, &layout, block);
+        }
+        for (i, (name, ptrs)) in self.free_lists.iter().enumerate() {
+            self.render_list(&mut svg, &layout, i, name, ptrs);
+        }
+        for (i, line) in self.stats.iter().enumerate() {
+            let _ = writeln!(
+                svg, "<text x=\"{}\" y=\"{}\">{}</text>",
+                MARGIN, stats_top + LINE * i as f32, escape(line));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    fn render_block(&self, svg: &mut String, layout: &Layout, block: &BlockDescription) {
+        let fill = match block.state {
+            BlockState::Allocated => ALLOCATED,
+            BlockState::Free | BlockState::Top => FREE,
+            BlockState::Tcache => TCACHE,
+            BlockState::Fastbin => FASTBIN,
+        };
+        let header = block.header.min(block.size);
+        let parts = [
+            (block.offset, block.size, fill, ""),
+            (block.offset, header, "#000000", " fill-opacity=\"0.5\""),
+            (block.offset + header,
+                if block.state == BlockState::Allocated {
+                    block.used.min(block.size - header)
+                } else {
+                    0
+                },
+                USED, ""),
+        ];
+        for (offset, size, color, extra) in parts.iter() {
+            for (x, y, w) in layout.segments(*offset, *size) {
+                let _ = writeln!(
+                    svg,
+                    "<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{}\" fill=\"{}\"{}/>",
+                    x, y, w, ROW_HEIGHT, color, extra);
+            }
+        }
+        // A thin white line between neighbors, as the canvas leaves a gap.
+        for (x, y, w) in layout.segments(block.offset, block.size) {
+            let _ = writeln!(
+                svg,
+                concat!("<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{}\"",
+                    " fill=\"none\" stroke=\"#ffffff\"/>"),
+                x, y, w, ROW_HEIGHT);
+        }
+
+        let (x, y, w) = match layout.segments(block.offset, block.size).first() {
+            Some(first) => *first,
+            None => return,
+        };
+        let mut labels = vec![];
+        match block.state {
+            BlockState::Allocated =>
+                labels.push((hex(self.base + block.offset + header), FONT + 2.0)),
+            BlockState::Top => labels.push((String::from("top"), FONT + 2.0)),
+            _ => {}
+        }
+        labels.push((format!("{} B", block.size), ROW_HEIGHT / 2.0 + FONT / 2.0));
+        labels.push((hex(self.base + block.offset), ROW_HEIGHT - 4.0));
+        for (label, dy) in labels.iter() {
+            if text_width(label) + 4.0 <= w {
+                let _ = writeln!(
+                    svg, "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"#ffffff\">{}</text>",
+                    x + 2.0, y + dy, label);
+            }
+        }
+    }
+
+    // Arrows from each chunk to the next one on the list, arching over the
+    // heap, with the list's name over its head.
+    fn render_list(
+            &self, svg: &mut String, layout: &Layout, i: usize, name: &str, ptrs: &[i32]) {
+        let color = LIST_COLORS[i % LIST_COLORS.len()];
+        let anchors: Vec<(f32, f32)> = ptrs.iter()
+            .filter_map(|ptr| self.blocks.iter()
+                .find(|b| self.base + b.offset + b.header.min(b.size) == *ptr))
+            .filter_map(|block| layout.segments(block.offset, block.size).first()
+                .map(|(x, y, w)| (x + w / 2.0, *y)))
+            .collect();
+        let (head_x, head_y) = match anchors.first() {
+            Some(head) => *head,
+            None => return,
+        };
+        // Stagger the lists' names so they don't overlap at a shared head.
+        let name_y = head_y - ARROW_SPACE + FONT + (i % 3) as f32 * FONT;
+        let _ = writeln!(
+            svg, "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"{}\">{}</text>",
+            head_x - text_width(name) / 2.0, name_y, color, escape(name));
+
+        for pair in anchors.windows(2) {
+            let ((x1, y1), (x2, y2)) = (pair[0], pair[1]);
+            let lift = ARROW_SPACE * 0.7;
+            let _ = writeln!(
+                svg,
+                concat!("<path d=\"M{:.2},{:.2} C{:.2},{:.2} {:.2},{:.2} {:.2},{:.2}\"",
+                    " fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"",
+                    " marker-end=\"url(#arrow{})\"/>"),
+                x1, y1, x1, y1 - lift, x2, y2 - lift, x2, y2,
+                color, i % LIST_COLORS.len());
+        }
+    }
+}
+
+// Where bytes of the heap end up in the picture.
+struct Layout {
+    px_per_byte: f32,
+    row_bytes: i32,
+}
+
+impl Layout {
+    fn row_top(&self, row: i32) -> f32 {
+        2.0 * MARGIN + ARROW_SPACE + row as f32 * (ROW_HEIGHT + ARROW_SPACE)
+    }
+
+    // The x, y and width of each piece of `size` bytes from `offset`, split
+    // where rows wrap.
+    fn segments(&self, offset: i32, size: i32) -> Vec<(f32, f32, f32)> {
+        let mut segments = vec![];
+        let (mut start, end) = (offset, offset + size);
+        while start < end {
+            let row = start / self.row_bytes;
+            let stop = end.min((row + 1) * self.row_bytes);
+            segments.push((
+                MARGIN + (start - row * self.row_bytes) as f32 * self.px_per_byte,
+                self.row_top(row),
+                (stop - start) as f32 * self.px_per_byte));
+            start = stop;
+        }
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::Heap;
+
+    fn block(
+            id: usize, offset: i32, size: i32, used: i32, state: BlockState)
+            -> BlockDescription {
+        BlockDescription {
+            id: id,
+            offset: offset,
+            size: size,
+            header: 16,
+            used: used,
+            state: state,
+            corrupted: false,
+            clobbered: 0,
+        }
+    }
+
+    fn frame() -> Frame {
+        Frame {
+            title: String::from("a <heap>"),
+            base: 0x1000,
+            blocks: vec![
+                block(0, 0, 48, 20, BlockState::Allocated),
+                block(1, 48, 80, 0, BlockState::Free),
+            ],
+            free_lists: vec![(String::from("free blocks"), vec![0x1040])],
+            stats: vec![],
+        }
+    }
+
+    fn count(svg: &str, pattern: &str) -> usize {
+        svg.matches(pattern).count()
+    }
+
+    #[test]
+    fn one_rect_per_block_segment() {
+        // The free block wraps onto the second row.
+        let svg = frame().render(600.0, 64);
+        assert_eq!(count(&svg, &format!("fill=\"{}\"/>", ALLOCATED)), 1);
+        assert_eq!(count(&svg, &format!("fill=\"{}\"/>", FREE)), 2);
+        assert_eq!(count(&svg, &format!("fill=\"{}\"/>", USED)), 1);
+        assert_eq!(count(&svg, "stroke=\"#ffffff\""), 3);
+
+        let strip = frame().render(600.0, 0);
+        assert_eq!(count(&strip, &format!("fill=\"{}\"/>", FREE)), 1);
+        assert_eq!(count(&strip, "stroke=\"#ffffff\""), 2);
+    }
+
+    #[test]
+    fn text_is_escaped_and_lists_are_labeled() {
+        let svg = frame().render(600.0, 0);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(">a &lt;heap&gt;</text>"));
+        assert!(svg.contains(">free blocks</text>"));
+    }
+
+    #[test]
+    fn stats_describe_the_heap() {
+        let mut heap = Heap::with_geometry(0x1000, 16, 16, 1 << 12);
+        heap.managed = true;
+        heap.sbrk(256).unwrap();
+        heap.malloc(40).unwrap();
+        let frame = Frame::of(&heap, heap.base, "heap");
+        assert_eq!(frame.stats, vec![
+            "heap 0x1000 to 0x1100, 256 B",
+            "40 B in use in 1 blocks",
+            "192 B free in 1 blocks, the largest 192 B",
+        ]);
+    }
+}
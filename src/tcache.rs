@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+// A thread's cache of recently freed blocks, binned by exact block size.
+// Frees of small blocks land here first and the next malloc of the same size
+// takes the most recently freed one back without touching the heap, which is
+// what makes glibc's tcache fast and its double frees so dangerous.
+pub struct Tcache {
+    // Block size -> pointers, most recently freed last.
+    pub bins: BTreeMap<i32, Vec<i32>>,
+    // Most blocks a single bin holds.
+    pub count: usize,
+    // Largest block size that is cached at all.
+    pub max_size: i32,
+}
+
+impl Tcache {
+    pub fn new(count: i32, max_size: i32) -> Self {
+        Tcache {
+            bins: BTreeMap::new(),
+            count: count as usize,
+            max_size: max_size,
+        }
+    }
+
+    // How many blocks of `size` bytes are cached, glibc's counts[tc_idx].
+    // Each bin fills up on its own.
+    pub fn held(&self, size: i32) -> usize {
+        self.bins.get(&size).map_or(0, |bin| bin.len())
+    }
+
+    // Whether freeing a block of `size` bytes would cache it.
+    pub fn has_room(&self, size: i32) -> bool {
+        size <= self.max_size && self.held(size) < self.count
+    }
+
+    pub fn put(&mut self, size: i32, ptr: i32) {
+        self.bins.entry(size).or_default().push(ptr);
+    }
+
+    pub fn take(&mut self, size: i32) -> Option<i32> {
+        let bin = self.bins.get_mut(&size)?;
+        let ptr = bin.pop();
+        if bin.is_empty() {
+            self.bins.remove(&size);
+        }
+        ptr
+    }
+
+    // One line listing every bin, e.g. "24 B [0x1018 0x1030]  48 B [0x1108]".
+    pub fn describe(&self) -> String {
+        let bins: Vec<String> = self.bins.iter()
+            .map(|(size, ptrs)| {
+                let ptrs: Vec<String> = ptrs.iter().map(|p| format!("0x{:x}", p)).collect();
+                format!("{} B [{}]", size, ptrs.join(" "))
+            })
+            .collect();
+        if bins.is_empty() {
+            String::from("empty")
+        } else {
+            bins.join("  ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bins_fill_up_separately() {
+        let mut tcache = Tcache::new(2, 64);
+        tcache.put(24, 0x1010);
+        tcache.put(24, 0x1030);
+        assert!(!tcache.has_room(24));
+        assert!(tcache.has_room(48));
+        tcache.put(48, 0x1050);
+        assert_eq!(tcache.held(24), 2);
+        assert_eq!(tcache.held(48), 1);
+    }
+
+    #[test]
+    fn take_is_lifo_and_frees_room() {
+        let mut tcache = Tcache::new(2, 64);
+        tcache.put(24, 0x1010);
+        tcache.put(24, 0x1030);
+        assert_eq!(tcache.take(24), Some(0x1030));
+        assert!(tcache.has_room(24));
+        assert_eq!(tcache.take(24), Some(0x1010));
+        assert_eq!(tcache.take(24), None);
+        assert!(tcache.bins.is_empty());
+    }
+
+    #[test]
+    fn large_blocks_are_not_cached() {
+        let tcache = Tcache::new(7, 64);
+        assert!(tcache.has_room(64));
+        assert!(!tcache.has_room(80));
+        assert!(!Tcache::new(0, 64).has_room(24));
+    }
+
+    #[test]
+    fn describe_lists_every_bin() {
+        let mut tcache = Tcache::new(7, 64);
+        assert_eq!(tcache.describe(), "empty");
+        tcache.put(24, 0x1018);
+        tcache.put(24, 0x1030);
+        tcache.put(48, 0x1108);
+        assert_eq!(tcache.describe(), "24 B [0x1018 0x1030]  48 B [0x1108]");
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{HashMap, hash_map::Entry};
+
+use quicksilver::{
+    Result,
+    geom::{Shape, Vector},
+    graphics::{Background::Blended, Color, Font, FontStyle, Image},
+    lifecycle::{Asset, Window}
+};
+
+// mononoki is monospaced, with every glyph half as wide as the font size.
+static ADVANCE: f32 = 0.5;
+
+// Draws arbitrary strings from a cache of glyphs. The font is loaded once,
+// and each glyph is rendered in white the first time it is needed at a size
+// and tinted to whatever color it is drawn in.
+pub struct Text {
+    font: Asset<Font>,
+    glyphs: HashMap<(char, u32), Image>,
+}
+
+impl Text {
+    pub fn new() -> Self {
+        Text {
+            font: Asset::new(Font::load("mononoki-Regular.ttf")),
+            glyphs: HashMap::new(),
+        }
+    }
+
+    pub fn width(text: &str, size: f32) -> f32 {
+        text.chars().count() as f32 * size * ADVANCE
+    }
+
+    pub fn size(text: &str, size: f32) -> Vector {
+        Vector::new(Text::width(text, size), size)
+    }
+
+    // Draws `text` with its top left corner at `pos`.
+    pub fn draw(
+            &mut self,
+            window: &mut Window,
+            text: &str,
+            pos: Vector,
+            size: f32,
+            color: Color) -> Result<()> {
+        let glyphs = &mut self.glyphs;
+        let advance = size * ADVANCE;
+        self.font.execute(|font| {
+            let style = FontStyle::new(size, Color::WHITE);
+            for (i, c) in text.chars().enumerate() {
+                if c.is_whitespace() {
+                    continue;
+                }
+                let key = (c, size.to_bits());
+                let glyph = match glyphs.entry(key) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) =>
+                        entry.insert(font.render(&c.to_string(), &style)?),
+                };
+                let cell = Vector::new(pos.x + i as f32 * advance, pos.y);
+                let area = glyph.area();
+                window.draw(
+                    &area.with_center(
+                        (cell.x + advance / 2.0, cell.y + area.height() / 2.0)),
+                    Blended(glyph, color));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn draw_centered(
+            &mut self,
+            window: &mut Window,
+            text: &str,
+            center: Vector,
+            size: f32,
+            color: Color) -> Result<()> {
+        let pos = center - Text::size(text, size) / 2.0;
+        self.draw(window, text, pos, size, color)
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::allocator::Allocator;
+use crate::heap::HeapError;
+
+// One request from a trace. Ids name allocations, so a later free or
+// realloc can find the pointer malloc returned.
+#[derive(Clone, Copy)]
+pub enum Op {
+    Malloc { id: usize, bytes: i32 },
+    Realloc { id: usize, bytes: i32 },
+    Free { id: usize },
+}
+
+// Written the way a trace file spells it.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Malloc { id, bytes } => write!(f, "a {} {}", id, bytes),
+            Op::Realloc { id, bytes } => write!(f, "r {} {}", id, bytes),
+            Op::Free { id } => write!(f, "f {}", id),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Trace {
+    pub ops: Vec<Op>,
+}
+
+impl Trace {
+    // Reads malloclab's .rep format: a header of plain numbers (suggested
+    // heap size, number of ids, number of ops, weight), which is skipped,
+    // then one `a id bytes`, `r id bytes` or `f id` per line.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut ops = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if ops.is_empty() && line.parse::<f64>().is_ok() {
+                continue;
+            }
+
+            let bad = || format!("Line {} of the trace isn't an op: {:?}", i + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let id = match words.get(1) {
+                Some(word) => word.parse::<usize>().map_err(|_| format!(
+                    "Line {} of the trace has an invalid id {:?}", i + 1, word))?,
+                None => return Err(bad()),
+            };
+            let bytes = || words.get(2).and_then(|w| w.parse::<i32>().ok()).ok_or_else(bad);
+            let op = match words[0] {
+                "a" => Op::Malloc { id: id, bytes: bytes()? },
+                "r" => Op::Realloc { id: id, bytes: bytes()? },
+                "f" => Op::Free { id: id },
+                _ => return Err(bad()),
+            };
+            ops.push(op);
+        }
+        Ok(Trace { ops: ops })
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for op in self.ops.iter() {
+            writeln!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+// Plays a trace against an allocator one op at a time.
+pub struct TraceRunner {
+    pub trace: Trace,
+    pub next: usize,
+    // Pointers currently held by each id.
+    pub ptrs: BTreeMap<usize, i32>,
+    // When set, a malloc or realloc that runs out of memory sbrks what it
+    // asked for plus this much and tries again, like mm_malloc extending
+    // the heap by CHUNKSIZE. Otherwise it just fails.
+    pub grow: i32,
+}
+
+impl TraceRunner {
+    pub fn new(trace: Trace) -> Self {
+        TraceRunner {
+            trace: trace,
+            next: 0,
+            ptrs: BTreeMap::new(),
+            grow: 0,
+        }
+    }
+
+    fn growing<F>(&self, allocator: &mut dyn Allocator, bytes: i32, call: F)
+            -> Result<i32, HeapError>
+            where F: Fn(&mut dyn Allocator) -> Result<i32, HeapError> {
+        match call(allocator) {
+            Err(HeapError::OutOfMemory(_)) if self.grow > 0 => {
+                // Near i32::MAX there's no room for the extra, and the
+                // sbrk fails on its own.
+                allocator.sbrk_request(bytes.checked_add(self.grow).unwrap_or(bytes))?;
+                call(allocator)
+            }
+            result => result,
+        }
+    }
+
+    // Runs the next op, returning it along with the pointer it produced.
+    // A realloc of an id that holds nothing is a malloc, as with NULL.
+    pub fn step(
+            &mut self,
+            allocator: &mut dyn Allocator) -> Option<(Op, Result<Option<i32>, HeapError>)> {
+        let op = *self.trace.ops.get(self.next)?;
+        self.next += 1;
+        let result = match op {
+            Op::Malloc { id, bytes } => {
+                let ptr = self.growing(allocator, bytes, |a| a.malloc(bytes));
+                ptr.map(|ptr| {
+                    self.ptrs.insert(id, ptr);
+                    Some(ptr)
+                })
+            }
+            Op::Realloc { id, bytes } => {
+                let moved = match self.ptrs.get(&id).cloned() {
+                    Some(ptr) =>
+                        self.growing(allocator, bytes, |a| a.realloc(ptr, bytes)),
+                    None => self.growing(allocator, bytes, |a| a.malloc(bytes)),
+                };
+                moved.map(|ptr| {
+                    self.ptrs.insert(id, ptr);
+                    Some(ptr)
+                })
+            }
+            Op::Free { id } => match self.ptrs.get(&id) {
+                Some(ptr) => allocator.free(*ptr).map(|_| {
+                    self.ptrs.remove(&id);
+                    None
+                }),
+                None => Err(HeapError::NoSuchBlock),
+            },
+        };
+        Some((op, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::policy;
+    use crate::config::Config;
+
+    #[test]
+    fn header_and_comments_are_skipped() {
+        let trace = Trace::parse("20000\n2\n3\n1\n# a comment\na 0 10\n\nr 0 20\nf 0\n")
+            .unwrap();
+        assert_eq!(trace.to_string(), "a 0 10\nr 0 20\nf 0\n");
+    }
+
+    #[test]
+    fn bad_lines_are_reported_by_number() {
+        let err = |text| Trace::parse(text).err().unwrap();
+        assert_eq!(err("a 0 10\nx 1 2\n"),
+            "Line 2 of the trace isn't an op: \"x 1 2\"");
+        assert_eq!(err("a 0\n"), "Line 1 of the trace isn't an op: \"a 0\"");
+        assert_eq!(err("a 0 10\nf -1\n"),
+            "Line 2 of the trace has an invalid id \"-1\"");
+    }
+
+    #[test]
+    fn frees_of_unknown_ids_fail() {
+        let trace = Trace::parse("f 3\n").unwrap();
+        let mut runner = TraceRunner::new(trace);
+        let mut bump = crate::bump::Bump::new();
+        let (_, result) = runner.step(&mut bump).unwrap();
+        assert!(matches!(result, Err(HeapError::NoSuchBlock)));
+        assert!(runner.step(&mut bump).is_none());
+    }
+
+    #[test]
+    fn huge_requests_run_out_of_memory() {
+        for name in ["first-fit", "ptmalloc"].iter() {
+            let config = Config { heap_capacity: 1 << 16, ..Config::default() };
+            let mut allocator = policy(name, &config).unwrap();
+            allocator.init(0x1000, config.heap_capacity);
+            let mut runner = TraceRunner::new(Trace::parse("a 0 2147483640\n").unwrap());
+            runner.grow = 4096;
+            let (_, result) = runner.step(allocator.as_mut()).unwrap();
+            assert!(matches!(result, Err(HeapError::HeapExhausted(_))));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use malloc_vis::config::Config;
+
+use quicksilver::geom::{Rectangle, Vector};
+
+// Pixels per byte at each zoom level. Levels below one pack several bytes
+// into a pixel so large heaps fit on screen.
+static ZOOM_LEVELS: [f32; 11] =
+    [1.0/32.0, 1.0/16.0, 1.0/8.0, 1.0/4.0, 1.0/2.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0];
+
+// Space between rows of a wrapped heap.
+static ROW_GAP: f32 = 10.0;
+
+// Room on the left of a wrapped heap for each row's start address.
+static GUTTER_PX: f32 = 100.0;
+
+// Room above the heap for the address ruler.
+pub static RULER_PX: f32 = 36.0;
+
+// Maps byte offsets in the heap to positions on the canvas. The heap is
+// either one long strip panned horizontally, or wrapped into rows of
+// `row_bytes` bytes that are scrolled through vertically.
+pub struct View {
+    level: usize,
+    pub px_per_byte: f32,
+    // Byte offset drawn at the left edge of the canvas.
+    pub first_byte: f32,
+    // Height of the heap strip, or of a single row when wrapped.
+    pub height: f32,
+    // Where the ruler starts, so several heaps can be stacked.
+    pub top: f32,
+    capacity: f32,
+
+    pub row_bytes: i32,
+    pub first_row: i32,
+    pub visible_rows: i32,
+}
+
+impl View {
+    // Starts at the zoom level closest to the configured one.
+    pub fn new(config: &Config) -> Self {
+        let level = ZOOM_LEVELS.iter()
+            .rposition(|z| *z <= config.px_per_byte)
+            .unwrap_or(0);
+        let wrapped = config.row_bytes > 0;
+        View {
+            level: level,
+            px_per_byte: ZOOM_LEVELS[level],
+            first_byte: 0.0,
+            height: if wrapped {
+                config.menu_px as f32 / 2.0
+            } else {
+                config.menu_px as f32
+            },
+            top: 0.0,
+            capacity: config.heap_capacity as f32,
+            row_bytes: config.row_bytes,
+            first_row: 0,
+            visible_rows: if wrapped { config.visible_rows } else { 1 },
+        }
+    }
+
+    pub fn wrapped(&self) -> bool {
+        self.row_bytes > 0
+    }
+
+    pub fn gutter(&self) -> f32 {
+        if self.wrapped() { GUTTER_PX } else { 0.0 }
+    }
+
+    fn row_pitch(&self) -> f32 {
+        self.height + ROW_GAP
+    }
+
+    // Total height taken up by the ruler and the heap, all visible rows
+    // included.
+    pub fn area_height(&self) -> f32 {
+        if self.wrapped() {
+            RULER_PX + self.visible_rows as f32 * self.row_pitch() - ROW_GAP
+        } else {
+            RULER_PX + self.height
+        }
+    }
+
+    pub fn bottom(&self) -> f32 {
+        self.top + self.area_height()
+    }
+
+    pub fn width(&self, bytes: f32) -> f32 {
+        bytes * self.px_per_byte
+    }
+
+    // Top left corner of the given byte.
+    pub fn point(&self, bytes: f32) -> Vector {
+        if !self.wrapped() {
+            return Vector::new(
+                (bytes - self.first_byte) * self.px_per_byte,
+                self.top + RULER_PX);
+        }
+
+        let row_bytes = self.row_bytes as f32;
+        let row = (bytes / row_bytes).floor();
+        let col = bytes - row * row_bytes;
+        Vector::new(
+            self.gutter() + col * self.px_per_byte,
+            self.top + RULER_PX
+                + (row - self.first_row as f32) * self.row_pitch())
+    }
+
+    // The rectangles covering `len` bytes starting at `start`, one per row
+    // the range touches.
+    pub fn segments(&self, start: f32, len: f32) -> Vec<Rectangle> {
+        if !self.wrapped() {
+            return vec![Rectangle::new(
+                self.point(start), (self.width(len), self.height))];
+        }
+
+        let row_bytes = self.row_bytes as f32;
+        let end = start + len;
+        let mut segments = vec![];
+        let mut pos = start;
+        while pos < end {
+            let row_end = ((pos / row_bytes).floor() + 1.0) * row_bytes;
+            let seg_end = row_end.min(end);
+            segments.push(Rectangle::new(
+                self.point(pos), (self.width(seg_end - pos), self.height)));
+            pos = seg_end;
+        }
+        segments
+    }
+
+    // First byte of every visible row, along with where the row starts.
+    pub fn rows(&self) -> Vec<(i32, Vector)> {
+        if !self.wrapped() {
+            return vec![];
+        }
+
+        (self.first_row..self.first_row + self.visible_rows)
+            .map(|row| row * self.row_bytes)
+            .filter(|bytes| (*bytes as f32) < self.capacity)
+            .map(|bytes| (bytes, self.point(bytes as f32)))
+            .collect()
+    }
+
+    pub fn bytes_at(&self, pos: Vector) -> f32 {
+        if !self.wrapped() {
+            return pos.x / self.px_per_byte + self.first_byte;
+        }
+
+        let row = ((pos.y - self.top - RULER_PX) / self.row_pitch()).floor()
+            + self.first_row as f32;
+        let col = ((pos.x - self.gutter()) / self.px_per_byte)
+            .max(0.0)
+            .min(self.row_bytes as f32);
+        row * self.row_bytes as f32 + col
+    }
+
+    // The range of bytes spanned by a `width` pixel wide strip, or by one
+    // wrapped row, clipped to the heap.
+    pub fn visible_bytes(&self, width: f32) -> (f32, f32) {
+        if self.wrapped() {
+            return (0.0, (self.row_bytes as f32).min(self.capacity));
+        }
+        let last = self.first_byte + width / self.px_per_byte;
+        (self.first_byte, last.min(self.capacity))
+    }
+
+    // Scrolls just far enough to bring the byte at `start` onto a `width`
+    // pixel wide canvas.
+    pub fn reveal(&mut self, start: f32, width: f32) {
+        if self.wrapped() {
+            let row = (start / self.row_bytes as f32) as i32;
+            if row < self.first_row {
+                self.first_row = row;
+            } else if row >= self.first_row + self.visible_rows {
+                self.first_row = row - self.visible_rows + 1;
+            }
+        } else {
+            let (first, last) = self.visible_bytes(width);
+            if start < first || start >= last {
+                self.first_byte = start;
+            }
+        }
+        self.clamp();
+    }
+
+    // Zooms in (positive steps) or out while keeping the byte under
+    // `anchor` in place. Wrapped rows always start at the gutter, so there
+    // the zoom only changes how wide each row is.
+    pub fn zoom(&mut self, steps: i32, anchor: Vector) {
+        let anchor_bytes = self.bytes_at(anchor);
+        let level = (self.level as i32 + steps)
+            .max(0)
+            .min(ZOOM_LEVELS.len() as i32 - 1);
+        self.level = level as usize;
+        self.px_per_byte = ZOOM_LEVELS[self.level];
+        self.first_byte = anchor_bytes - anchor.x / self.px_per_byte;
+        self.clamp();
+    }
+
+    // Pans along the strip by `delta` pixels, or scrolls a wrapped heap by
+    // a row in the direction of `delta`.
+    pub fn pan(&mut self, delta: f32) {
+        if self.wrapped() {
+            self.first_row += if delta > 0.0 { 1 } else { -1 };
+        } else {
+            self.first_byte += delta / self.px_per_byte;
+        }
+        self.clamp();
+    }
+
+    fn clamp(&mut self) {
+        self.first_byte = self.first_byte.max(0.0).min(self.capacity);
+        if self.wrapped() {
+            let last_row = (self.capacity as i32 - 1) / self.row_bytes;
+            self.first_row = self.first_row.max(0).min(last_row);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use stdweb::Value;
+use stdweb::unstable::TryInto;
+
+use malloc_vis::allocator::{Allocator, BlockDescription, BlockState};
+use malloc_vis::heap::{BlockId, HeapError};
+
+// Runs a malloclab allocator compiled to WebAssembly and loaded on the page,
+// see `mallocVis` in index.html. The module exports mm_init, mm_malloc,
+// mm_free and mm_realloc, and gets mem_sbrk and the rest of memlib from
+// "env". To have its blocks drawn it also exports
+//
+//     int mm_walk(int i, int *info)
+//
+// which stores the i-th block's address, size in bytes, payload pointer and
+// whether it is allocated in info[0..4], and returns 0 once i is past the
+// last block. Without it only the allocations themselves are drawn.
+pub struct WasmAllocator {
+    base: i32,
+    capacity: i32,
+    // Which instance on the page this is, and the module it was made from,
+    // since loading another module starts everything over.
+    handle: Option<(i32, i32)>,
+    // Where the heap starts in the module's linear memory.
+    lo: i32,
+    // Bytes asked for by each live allocation, by pointer.
+    live: BTreeMap<i32, i32>,
+    freed: Vec<i32>,
+}
+
+fn check(value: Value) -> Result<i32, HeapError> {
+    match value {
+        Value::String(err) => Err(HeapError::External(err)),
+        value => value.try_into().map_err(|_| HeapError::External(
+            String::from("The module returned something that isn't a number."))),
+    }
+}
+
+impl WasmAllocator {
+    pub fn new() -> Self {
+        WasmAllocator {
+            base: 0,
+            capacity: 0,
+            handle: None,
+            lo: 0,
+            live: BTreeMap::new(),
+            freed: vec![],
+        }
+    }
+
+    // The running instance, made and given to mm_init on first use, or
+    // again once a different module has been loaded.
+    fn instance(&mut self) -> Result<i32, HeapError> {
+        let generation = check(js! {
+            return mallocVis.module ? mallocVis.generation : -1;
+        })?;
+        if generation < 0 {
+            return Err(HeapError::External(
+                String::from("Load a wasm allocator on the page first.")));
+        }
+        match self.handle {
+            Some((handle, loaded)) if loaded == generation => return Ok(handle),
+            _ => {}
+        }
+
+        let (base, capacity) = (self.base, self.capacity);
+        self.init(base, capacity);
+        let started: Value = js! {
+            return mallocVis.instantiate(@{capacity});
+        };
+        let started: Vec<i32> = match started {
+            Value::String(err) => return Err(HeapError::External(err)),
+            value => value.try_into().unwrap_or_default(),
+        };
+        // The page answers with the instance's handle and where its heap
+        // starts, anything else means index.html is out of date.
+        let (handle, lo) = match started.get(0..2) {
+            Some(&[handle, lo]) => (handle, lo),
+            _ => return Err(HeapError::External(String::from(
+                "The page didn't return an instance and its heap's address."))),
+        };
+        self.lo = lo;
+        if self.call(handle, "mm_init", 0, 0)? < 0 {
+            return Err(HeapError::External(String::from("mm_init failed.")));
+        }
+        self.handle = Some((handle, generation));
+        Ok(handle)
+    }
+
+    fn call(&self, handle: i32, name: &str, a: i32, b: i32) -> Result<i32, HeapError> {
+        check(js! {
+            return mallocVis.call(@{handle}, @{name}, @{a}, @{b});
+        })
+    }
+
+    fn to_module(&self, ptr: i32) -> i32 {
+        ptr - self.base + self.lo
+    }
+
+    fn from_module(&self, addr: i32) -> i32 {
+        addr - self.lo + self.base
+    }
+
+    // Catches frees the module would have to trust, so a bad pointer is
+    // explained rather than corrupting the student's heap.
+    fn check_live(&self, ptr: i32) -> Result<(), HeapError> {
+        if ptr < self.base || ptr >= self.base + self.capacity {
+            Err(HeapError::OutOfHeap(ptr))
+        } else if self.live.contains_key(&ptr) {
+            Ok(())
+        } else if self.freed.contains(&ptr) {
+            Err(HeapError::DoubleFree(ptr))
+        } else {
+            Err(HeapError::InvalidPointer(ptr))
+        }
+    }
+}
+
+impl Allocator for WasmAllocator {
+    fn name(&self) -> String {
+        String::from("wasm")
+    }
+
+    fn init(&mut self, base: i32, capacity: i32) {
+        *self = WasmAllocator::new();
+        self.base = base;
+        self.capacity = capacity;
+    }
+
+    fn malloc(&mut self, bytes: i32) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        let handle = self.instance()?;
+        let addr = self.call(handle, "mm_malloc", bytes, 0)?;
+        if addr == 0 {
+            return Err(HeapError::OutOfMemory(bytes));
+        }
+        let ptr = self.from_module(addr);
+        self.live.insert(ptr, bytes);
+        self.freed.retain(|p| *p != ptr);
+        Ok(ptr)
+    }
+
+    fn free(&mut self, ptr: i32) -> Result<(), HeapError> {
+        let handle = self.instance()?;
+        self.check_live(ptr)?;
+        self.call(handle, "mm_free", self.to_module(ptr), 0)?;
+        self.live.remove(&ptr);
+        self.freed.push(ptr);
+        Ok(())
+    }
+
+    fn realloc(&mut self, ptr: i32, bytes: i32) -> Result<i32, HeapError> {
+        if bytes < 0 {
+            return Err(HeapError::NegativeSize);
+        }
+        let handle = self.instance()?;
+        self.check_live(ptr)?;
+        let addr = self.call(handle, "mm_realloc", self.to_module(ptr), bytes)?;
+        if addr == 0 {
+            return Err(HeapError::OutOfMemory(bytes));
+        }
+        let new_ptr = self.from_module(addr);
+        self.live.remove(&ptr);
+        if new_ptr != ptr {
+            self.freed.push(ptr);
+        }
+        self.live.insert(new_ptr, bytes);
+        Ok(new_ptr)
+    }
+
+    // The module grows its heap itself by calling mem_sbrk.
+    fn sbrk_request(&mut self, _bytes: i32) -> Result<i32, HeapError> {
+        Err(HeapError::Unsupported("sbrk from outside the module"))
+    }
+
+    fn describe_blocks(&self) -> Vec<BlockDescription> {
+        let handle = match self.handle {
+            Some((handle, _)) => handle,
+            None => return vec![],
+        };
+        let walked: Value = js! {
+            return mallocVis.walk(@{handle});
+        };
+        let walked: Vec<i32> = walked.try_into().unwrap_or_default();
+
+        if walked.is_empty() {
+            return self.live.iter().map(|(ptr, bytes)| BlockDescription {
+                id: *ptr as BlockId,
+                offset: ptr - self.base,
+                size: *bytes,
+                header: 0,
+                used: *bytes,
+                state: BlockState::Allocated,
+                corrupted: false,
+                clobbered: 0,
+            }).collect();
+        }
+        walked.chunks(4).filter(|info| info.len() == 4).map(|info| {
+            let (start, size, payload) = (info[0] - self.lo, info[1], info[2] - self.lo);
+            let ptr = self.base + payload;
+            let allocated = info[3] != 0;
+            BlockDescription {
+                id: start as BlockId,
+                offset: start,
+                size: size,
+                header: payload - start,
+                used: match self.live.get(&ptr) {
+                    Some(bytes) => *bytes,
+                    None if allocated => size - (payload - start),
+                    None => 0,
+                },
+                state: if allocated {
+                    BlockState::Allocated
+                } else {
+                    BlockState::Free
+                },
+                corrupted: false,
+                clobbered: 0,
+            }
+        }).collect()
+    }
+}
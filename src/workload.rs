@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::trace::{Op, Trace};
+
+// SplitMix64, so the same seed gives the same trace on every platform
+// without pulling in a crate for it.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in lo..=hi.
+    pub fn range(&mut self, lo: i32, hi: i32) -> i32 {
+        lo + (self.next_u64() % (hi - lo + 1).max(1) as u64) as i32
+    }
+}
+
+// How many bytes each request asks for.
+#[derive(Clone, Copy)]
+pub enum Sizes {
+    Uniform { min: i32, max: i32 },
+    // Mostly small requests with a long tail, averaging `mean`.
+    Exponential { mean: i32 },
+    // A bounded Pareto: nearly everything close to `min`, and now and then
+    // something close to `max`. Smaller `alpha` makes big requests likelier.
+    PowerLaw { min: i32, max: i32, alpha: f64 },
+}
+
+impl Sizes {
+    pub fn sample(&self, rng: &mut Rng) -> i32 {
+        match *self {
+            Sizes::Uniform { min, max } => rng.range(min, max),
+            Sizes::Exponential { mean } => {
+                let bytes = -(1.0 - rng.unit()).ln() * mean as f64;
+                (bytes.ceil() as i32).max(1)
+            }
+            Sizes::PowerLaw { min, max, alpha } => {
+                let (lo, hi) = ((min as f64).powf(alpha), (max as f64).powf(alpha));
+                let u = rng.unit();
+                let bytes = (-(u * hi - u * lo - hi) / (hi * lo)).powf(-1.0 / alpha);
+                (bytes.round() as i32).max(min).min(max)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Sizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sizes::Uniform { min, max } => write!(f, "uniform:{}-{}", min, max),
+            Sizes::Exponential { mean } => write!(f, "exp:{}", mean),
+            Sizes::PowerLaw { min, max, alpha } =>
+                write!(f, "power:{}-{}:{}", min, max, alpha),
+        }
+    }
+}
+
+// When blocks are freed relative to when they were allocated.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pattern {
+    // Newest first, like a stack of scopes.
+    Lifo,
+    // Oldest first, like a queue.
+    Fifo,
+    // Messages made in bursts and consumed in order, each leaving behind a
+    // small result that outlives it, so freed messages leave holes between
+    // the results.
+    ProducerConsumer,
+    // Everything allocated, then everything freed in random order.
+    RampAndRelease,
+    // Strings grown by doubling with realloc while small objects are made
+    // in between, then kept or thrown away.
+    StringBuilder,
+}
+
+pub static PATTERNS: [&str; 5] = [
+    "lifo", "fifo", "producer-consumer", "ramp", "string-builder",
+];
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Pattern::Lifo => PATTERNS[0],
+            Pattern::Fifo => PATTERNS[1],
+            Pattern::ProducerConsumer => PATTERNS[2],
+            Pattern::RampAndRelease => PATTERNS[3],
+            Pattern::StringBuilder => PATTERNS[4],
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// A recipe for a trace, written e.g. `fifo,exp:64,n=500,seed=7`: the
+// pattern, then optionally the sizes, how many blocks (or strings) to
+// allocate and the seed.
+#[derive(Clone, Copy)]
+pub struct Workload {
+    pub pattern: Pattern,
+    pub sizes: Sizes,
+    pub count: usize,
+    pub seed: u64,
+}
+
+fn number<T: std::str::FromStr>(what: &str, text: &str) -> Result<T, String> {
+    text.trim().parse()
+        .map_err(|_| format!("Invalid {} {:?} in the workload", what, text))
+}
+
+fn bounds(text: &str) -> Result<(i32, i32), String> {
+    let mut parts = text.splitn(2, '-');
+    let min = number("size", parts.next().unwrap_or(""))?;
+    let max = number("size", parts.next().unwrap_or(""))?;
+    if min <= 0 || max < min {
+        return Err(format!("Invalid size range {:?} in the workload", text));
+    }
+    Ok((min, max))
+}
+
+impl Workload {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut words = spec.split(',').map(|word| word.trim());
+        let pattern = match words.next().unwrap_or("") {
+            "lifo" => Pattern::Lifo,
+            "fifo" => Pattern::Fifo,
+            "producer-consumer" => Pattern::ProducerConsumer,
+            "ramp" => Pattern::RampAndRelease,
+            "string-builder" => Pattern::StringBuilder,
+            name => return Err(format!(
+                "Unknown workload {:?}, expected one of {}", name, PATTERNS.join(", "))),
+        };
+        let mut workload = Workload {
+            pattern: pattern,
+            sizes: Sizes::Uniform { min: 16, max: 256 },
+            count: 100,
+            seed: 1,
+        };
+
+        for word in words {
+            let mut parts = word.splitn(2, [':', '=']);
+            let (key, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            match key {
+                "uniform" => {
+                    let (min, max) = bounds(value)?;
+                    workload.sizes = Sizes::Uniform { min: min, max: max };
+                }
+                "exp" => {
+                    let mean = number("mean size", value)?;
+                    if mean <= 0 {
+                        return Err(format!("Invalid mean size {} in the workload", mean));
+                    }
+                    workload.sizes = Sizes::Exponential { mean: mean };
+                }
+                "power" => {
+                    let mut parts = value.splitn(2, ':');
+                    let (min, max) = bounds(parts.next().unwrap_or(""))?;
+                    let alpha = match parts.next() {
+                        Some(alpha) => number::<f64>("exponent", alpha)?,
+                        None => 1.5,
+                    };
+                    if alpha.is_nan() || alpha <= 0.0 {
+                        return Err(format!("Invalid exponent {} in the workload", alpha));
+                    }
+                    workload.sizes = Sizes::PowerLaw { min: min, max: max, alpha: alpha };
+                }
+                "n" => workload.count = number("count", value)?,
+                "seed" => workload.seed = number("seed", value)?,
+                _ => return Err(format!("Unknown part {:?} of the workload", word)),
+            }
+        }
+        Ok(workload)
+    }
+
+    pub fn generate(&self) -> Trace {
+        let mut gen = Generator {
+            ops: vec![],
+            next_id: 0,
+            rng: Rng::new(self.seed),
+            sizes: self.sizes,
+        };
+        match self.pattern {
+            Pattern::Lifo | Pattern::Fifo => gen.lifetimes(self.count, self.pattern),
+            Pattern::ProducerConsumer => gen.producer_consumer(self.count),
+            Pattern::RampAndRelease => gen.ramp_and_release(self.count),
+            Pattern::StringBuilder => gen.string_builder(self.count),
+        }
+        Trace { ops: gen.ops }
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},n={},seed={}", self.pattern, self.sizes, self.count, self.seed)
+    }
+}
+
+// Writes ops, handing out trace ids as it goes. Every generated trace frees
+// everything it allocated by the end.
+struct Generator {
+    ops: Vec<Op>,
+    next_id: usize,
+    rng: Rng,
+    sizes: Sizes,
+}
+
+impl Generator {
+    fn malloc(&mut self, bytes: i32) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ops.push(Op::Malloc { id: id, bytes: bytes });
+        id
+    }
+
+    fn malloc_sized(&mut self) -> usize {
+        let bytes = self.sizes.sample(&mut self.rng);
+        self.malloc(bytes)
+    }
+
+    fn free(&mut self, id: usize) {
+        self.ops.push(Op::Free { id: id });
+    }
+
+    fn lifetimes(&mut self, count: usize, pattern: Pattern) {
+        let next_freed = |live: &mut VecDeque<usize>| match pattern {
+            Pattern::Lifo => live.pop_back(),
+            _ => live.pop_front(),
+        };
+        let mut live = VecDeque::new();
+        for _ in 0..count {
+            let id = self.malloc_sized();
+            live.push_back(id);
+            if self.rng.unit() < 0.4 {
+                for _ in 0..self.rng.range(1, 3) {
+                    if let Some(id) = next_freed(&mut live) {
+                        self.free(id);
+                    }
+                }
+            }
+        }
+        while let Some(id) = next_freed(&mut live) {
+            self.free(id);
+        }
+    }
+
+    fn producer_consumer(&mut self, count: usize) {
+        let mut queue = VecDeque::new();
+        let mut results = vec![];
+        let mut produced = 0;
+        while produced < count || !queue.is_empty() {
+            let burst = (self.rng.range(1, 8) as usize).min(count - produced);
+            for _ in 0..burst {
+                let id = self.malloc_sized();
+                queue.push_back(id);
+            }
+            produced += burst;
+
+            let consumed = if produced < count { self.rng.range(1, 6) } else { 8 };
+            for _ in 0..consumed {
+                if let Some(message) = queue.pop_front() {
+                    results.push(self.malloc(32));
+                    self.free(message);
+                }
+            }
+        }
+        for id in results {
+            self.free(id);
+        }
+    }
+
+    fn ramp_and_release(&mut self, count: usize) {
+        let mut live: Vec<usize> = (0..count).map(|_| self.malloc_sized()).collect();
+        // Fisher-Yates, so the frees come in random order.
+        for i in (1..live.len()).rev() {
+            let j = self.rng.range(0, i as i32) as usize;
+            live.swap(i, j);
+        }
+        for id in live {
+            self.free(id);
+        }
+    }
+
+    fn string_builder(&mut self, count: usize) {
+        let mut kept = vec![];
+        for _ in 0..count {
+            let length = self.sizes.sample(&mut self.rng);
+            let mut capacity = 16;
+            let id = self.malloc(capacity);
+            while capacity < length {
+                // Doubling past the length would overflow for huge strings.
+                capacity = capacity.saturating_mul(2).min(length);
+                self.ops.push(Op::Realloc { id: id, bytes: capacity });
+                if self.rng.unit() < 0.3 {
+                    let bytes = self.rng.range(8, 64);
+                    kept.push(self.malloc(bytes));
+                }
+            }
+            // Shrink to fit once the string is done.
+            if capacity != length {
+                self.ops.push(Op::Realloc { id: id, bytes: length });
+            }
+            if self.rng.unit() < 0.5 {
+                kept.push(id);
+            } else {
+                self.free(id);
+            }
+        }
+        for id in kept {
+            self.free(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(spec: &str) -> Trace {
+        Workload::parse(spec).unwrap().generate()
+    }
+
+    #[test]
+    fn the_seed_decides_the_trace() {
+        let spec = "producer-consumer,exp:64,n=50,seed=7";
+        assert_eq!(trace(spec).to_string(), trace(spec).to_string());
+        assert_ne!(trace(spec).to_string(),
+            trace("producer-consumer,exp:64,n=50,seed=8").to_string());
+    }
+
+    #[test]
+    fn specs_round_trip_through_display() {
+        for spec in [
+            "lifo,uniform:8-64,n=10,seed=3",
+            "ramp,exp:100,n=5,seed=1",
+            "string-builder,power:16-4096:1.5,n=3,seed=2",
+        ].iter() {
+            let workload = Workload::parse(spec).unwrap();
+            assert_eq!(workload.to_string(), *spec);
+            let again = Workload::parse(&workload.to_string()).unwrap();
+            assert_eq!(again.generate().to_string(), workload.generate().to_string());
+        }
+    }
+
+    // Checks that every free takes the live block `pick` chooses.
+    fn frees_in_order(spec: &str, pick: fn(&[usize]) -> usize) {
+        let mut live = vec![];
+        for op in trace(spec).ops {
+            match op {
+                Op::Malloc { id, .. } => live.push(id),
+                Op::Free { id } => {
+                    assert_eq!(id, pick(&live));
+                    live.retain(|l| *l != id);
+                }
+                Op::Realloc { .. } => {}
+            }
+        }
+        assert!(live.is_empty());
+    }
+
+    #[test]
+    fn lifo_frees_newest_first_and_fifo_oldest_first() {
+        frees_in_order("lifo,n=100,seed=5", |live| live[live.len() - 1]);
+        frees_in_order("fifo,n=100,seed=5", |live| live[0]);
+    }
+
+    fn reallocs_of(trace: &Trace, block: usize) -> Vec<i32> {
+        trace.ops.iter().filter_map(|op| match *op {
+            Op::Realloc { id, bytes } if id == block => Some(bytes),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn strings_double_up_to_their_length() {
+        let trace = trace("string-builder,uniform:100-100,n=1");
+        assert_eq!(reallocs_of(&trace, 0), vec![32, 64, 100]);
+    }
+
+    #[test]
+    fn huge_strings_stop_at_their_length() {
+        let trace = trace("string-builder,uniform:1073741825-2000000000,n=1");
+        let reallocs = reallocs_of(&trace, 0);
+        assert!(reallocs.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(reallocs[reallocs.len() - 1] > 1 << 30);
+    }
+}